@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+
+use anyhow::Error;
+use serde::Serialize;
+
+use crate::{now, wire, LightningBackend, PluginState};
+
+/// Traffic through a channel, or the whole node, over a window.  Volume and
+/// fees only count settled forwards; the outcome counts are for forwards
+/// that tried to leave through the channel.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Flow {
+    pub in_msat: u64,
+    pub out_msat: u64,
+    pub fee_msat: u64,
+    pub settled: u64,
+    pub failed: u64,
+    pub local_failed: u64,
+    /// Share of the attempts that failed at our node, e.g. because the
+    /// channel didn't have the balance its htlc_max promised.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_failed_rate: Option<f64>,
+}
+
+impl Flow {
+    fn record_outcome(&mut self, status: &wire::ForwardStatus) {
+        match status {
+            wire::ForwardStatus::Settled => self.settled += 1,
+            wire::ForwardStatus::Failed => self.failed += 1,
+            wire::ForwardStatus::LocalFailed => self.local_failed += 1,
+            wire::ForwardStatus::Offered => {}
+        }
+    }
+
+    fn finish(&mut self) {
+        let attempts = self.settled + self.failed + self.local_failed;
+        self.local_failed_rate = if attempts > 0 {
+            Some(self.local_failed as f64 / attempts as f64)
+        } else {
+            None
+        };
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct WindowReport {
+    /// Length of the window in seconds.
+    pub window: u64,
+    pub since: u64,
+    pub total: Flow,
+    pub channels: BTreeMap<String, Flow>,
+}
+
+/// Parses a comma separated list of window lengths, in seconds or with an
+/// `m`, `h` or `d` suffix, e.g. `1d,7d,30d`.
+pub fn parse_windows(s: &str) -> Result<Vec<u64>, String> {
+    s.split(',')
+        .map(|w| {
+            let w = w.trim();
+            let (number, unit) = match w.char_indices().last() {
+                Some((i, 's')) => (&w[..i], 1),
+                Some((i, 'm')) => (&w[..i], 60),
+                Some((i, 'h')) => (&w[..i], 3600),
+                Some((i, 'd')) => (&w[..i], 86400),
+                _ => (w, 1),
+            };
+            match number.parse::<u64>() {
+                Ok(n) if n > 0 => n
+                    .checked_mul(unit)
+                    .ok_or_else(|| format!("window {:?} is too long", w)),
+                _ => Err(format!("invalid window {:?}", w)),
+            }
+        })
+        .collect()
+}
+
+/// Aggregates the forwards received within each window ending at `now`.
+pub fn aggregate(forwards: &[wire::Forward], now: u64, windows: &[u64]) -> Vec<WindowReport> {
+    windows
+        .iter()
+        .map(|&window| {
+            let since = now.saturating_sub(window);
+            let mut total = Flow::default();
+            let mut channels: BTreeMap<String, Flow> = BTreeMap::new();
+            for forward in forwards.iter().filter(|f| f.received_time >= since as f64) {
+                total.record_outcome(&forward.status);
+                // Failures that never got as far as picking an outgoing
+                // channel only show up in the total
+                if let Some(out_channel) = &forward.out_channel {
+                    channels
+                        .entry(out_channel.clone())
+                        .or_default()
+                        .record_outcome(&forward.status);
+                }
+                if forward.status != wire::ForwardStatus::Settled {
+                    continue;
+                }
+                let in_msat = forward.in_msat.map(|a| a.msat()).unwrap_or(0);
+                let out_msat = forward.out_msat.map(|a| a.msat()).unwrap_or(0);
+                let fee_msat = forward
+                    .fee_msat
+                    .map(|a| a.msat())
+                    .unwrap_or_else(|| in_msat.saturating_sub(out_msat));
+                total.in_msat += in_msat;
+                total.out_msat += out_msat;
+                total.fee_msat += fee_msat;
+                channels
+                    .entry(forward.in_channel.clone())
+                    .or_default()
+                    .in_msat += in_msat;
+                if let Some(out_channel) = &forward.out_channel {
+                    let flow = channels.entry(out_channel.clone()).or_default();
+                    flow.out_msat += out_msat;
+                    // The fee is earned for the liquidity the outgoing
+                    // channel gave up
+                    flow.fee_msat += fee_msat;
+                }
+            }
+            total.finish();
+            channels.values_mut().for_each(Flow::finish);
+            WindowReport {
+                window,
+                since,
+                total,
+                channels,
+            }
+        })
+        .collect()
+}
+
+/// Everything `ceebalancer-report` returns.
+pub async fn flow_report<B: LightningBackend>(
+    state: &PluginState<B>,
+    windows: &[u64],
+) -> Result<Vec<WindowReport>, Error> {
+    let forwards = state.backend.list_forwards().await?;
+    Ok(aggregate(&forwards, now(), windows))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primitives::Amount;
+
+    fn forward(
+        out_channel: Option<&str>,
+        msat: u64,
+        status: wire::ForwardStatus,
+        received_time: f64,
+    ) -> wire::Forward {
+        wire::Forward {
+            in_channel: "1x1x1".to_string(),
+            out_channel: out_channel.map(|c| c.to_string()),
+            in_msat: Some(Amount::from_msat(msat + 10)),
+            out_msat: out_channel.map(|_| Amount::from_msat(msat)),
+            fee_msat: None,
+            status,
+            received_time,
+            resolved_time: None,
+        }
+    }
+
+    #[test]
+    fn windows_parse_with_units() {
+        assert_eq!(
+            parse_windows("90,2m,1h,7d"),
+            Ok(vec![90, 120, 3600, 604800])
+        );
+        assert!(parse_windows("1w").is_err());
+        assert!(parse_windows("0d").is_err());
+        assert!(parse_windows("999999999999999999d").is_err());
+        assert!(parse_windows("").is_err());
+    }
+
+    #[test]
+    fn aggregates_by_channel_and_window() {
+        use wire::ForwardStatus::*;
+        let forwards = vec![
+            forward(Some("2x2x2"), 1000, Settled, 950.0),
+            forward(Some("2x2x2"), 5000, LocalFailed, 960.0),
+            forward(Some("2x2x2"), 5000, Failed, 970.0),
+            forward(None, 5000, LocalFailed, 980.0),
+            forward(Some("2x2x2"), 2000, Settled, 100.0),
+        ];
+        let reports = aggregate(&forwards, 1000, &[100, 1000]);
+
+        let recent = &reports[0];
+        assert_eq!(recent.since, 900);
+        assert_eq!(recent.total.settled, 1);
+        assert_eq!(recent.total.local_failed, 2);
+        assert_eq!(recent.total.fee_msat, 10);
+        assert_eq!(recent.channels["1x1x1"].in_msat, 1010);
+        let out = &recent.channels["2x2x2"];
+        assert_eq!(out.out_msat, 1000);
+        assert_eq!(out.fee_msat, 10);
+        assert_eq!((out.settled, out.failed, out.local_failed), (1, 1, 1));
+        assert_eq!(out.local_failed_rate, Some(1.0 / 3.0));
+
+        assert_eq!(reports[1].channels["2x2x2"].out_msat, 3000);
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::future::Future;
+
+use anyhow::Error;
+
+use crate::wire;
+use crate::ChannelPolicy;
+
+/// The node the policy engine is steering.  Everything in `lib.rs` talks to
+/// the node through this, so the same fee and htlc_max logic can run against
+/// Core Lightning, another implementation, or a fake in tests.
+pub trait LightningBackend: Clone + Send + Sync + 'static {
+    /// Raw `getinfo` style description of the node, for logging.
+    fn get_info(&self) -> impl Future<Output = Result<String, Error>> + Send;
+
+    fn get_node_id(&self) -> impl Future<Output = Result<String, Error>> + Send;
+
+    /// Our channels with their balances.
+    fn list_channels(&self) -> impl Future<Output = Result<Vec<wire::Channel>, Error>> + Send;
+
+    /// What each of our channels currently advertises, keyed by
+    /// short_channel_id.
+    fn list_channel_policies(
+        &self,
+        node_id: &str,
+    ) -> impl Future<Output = Result<HashMap<String, ChannelPolicy>, Error>> + Send;
+
+    /// Every channel in the gossip graph into `node_id`, each with the
+    /// policy its other end advertises, ours included.
+    fn list_channels_to(
+        &self,
+        node_id: &str,
+    ) -> impl Future<Output = Result<Vec<wire::ListChannel>, Error>> + Send;
+
+    fn set_channel_policy(
+        &self,
+        short_channel_id: &str,
+        policy: &ChannelPolicy,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Confirmed and unconfirmed on-chain funds, in msat.
+    fn onchain_balance(&self) -> impl Future<Output = Result<u64, Error>> + Send;
+
+    fn list_forwards(&self) -> impl Future<Output = Result<Vec<wire::Forward>, Error>> + Send;
+
+    /// Payments we've sent that completed, including the circular ones.
+    fn list_sendpays(&self) -> impl Future<Output = Result<Vec<wire::SendPay>, Error>> + Send;
+
+    /// One of our invoices, by payment hash.
+    fn get_invoice(
+        &self,
+        payment_hash: &str,
+    ) -> impl Future<Output = Result<Option<wire::ListInvoice>, Error>> + Send;
+
+    /// Both directions of a channel in the gossip graph.
+    fn get_channel(
+        &self,
+        short_channel_id: &str,
+    ) -> impl Future<Output = Result<Vec<wire::ListChannel>, Error>> + Send;
+
+    /// Cheapest route from `from` to `to`, avoiding the `exclude`d channels
+    /// (`short_channel_id/direction`) and nodes.  The first hop leaves
+    /// `from`.
+    fn get_route(
+        &self,
+        from: &str,
+        to: &str,
+        amount_msat: u64,
+        exclude: &[String],
+    ) -> impl Future<Output = Result<Vec<wire::RouteHop>, Error>> + Send;
+
+    fn create_invoice(
+        &self,
+        amount_msat: u64,
+        label: &str,
+        description: &str,
+    ) -> impl Future<Output = Result<wire::Invoice, Error>> + Send;
+
+    fn delete_invoice(&self, label: &str) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Pays `invoice` along exactly `route` and waits for the outcome.  A
+    /// failed payment is an error.
+    fn send_to_route(
+        &self,
+        route: &[wire::RouteHop],
+        invoice: &wire::Invoice,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+}
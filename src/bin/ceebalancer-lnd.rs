@@ -0,0 +1,86 @@
+//! Standalone daemon for LND, which has no plugin system.  Takes a JSON file
+//! with the LND connection details and the same options the Core Lightning
+//! plugin takes, e.g.
+//!
+//! ```json
+//! {
+//!     "lnd-url": "https://localhost:10009",
+//!     "lnd-tls-cert": "/home/lnd/.lnd/tls.cert",
+//!     "lnd-macaroon": "/home/lnd/.lnd/data/chain/bitcoin/mainnet/admin.macaroon",
+//!     "state-dir": "/var/lib/ceebalancer",
+//!     "dynamic-fees": true,
+//!     "dynamic-fee-max": 2000
+//! }
+//! ```
+use std::time::Duration;
+
+use anyhow::{anyhow, Error};
+
+use ceebalancer::lnd_client::LndBackend;
+use ceebalancer::settings::{option_value, parse_configuration};
+use ceebalancer::{set_channel_fees, Config, LightningBackend, PluginState};
+
+/// There's no lightningd to hand our logs to, so they go to stderr.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{} {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+fn setting<'a>(file: &'a serde_json::Value, name: &str) -> Result<&'a str, Error> {
+    file.get(name)
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing '{}' in the config file", name))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    log::set_logger(&LOGGER).map_err(|_| anyhow!("Unable to set up logging"))?;
+    log::set_max_level(log::LevelFilter::Info);
+    let path = std::env::args()
+        .nth(1)
+        .ok_or_else(|| anyhow!("Usage: ceebalancer-lnd <config.json>"))?;
+    let file: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+
+    let backend = LndBackend::new(
+        setting(&file, "lnd-url")?,
+        setting(&file, "lnd-tls-cert")?,
+        setting(&file, "lnd-macaroon")?,
+    )?;
+    let state = PluginState::new(backend);
+    match file.get("state-dir").and_then(|v| v.as_str()) {
+        Some(dir) => state.initialize(dir)?,
+        None => log::warn!("No 'state-dir' in the config file, history will not persist"),
+    }
+
+    parse_configuration(|name| file.get(name).and_then(option_value))?.make_current();
+    log::info!("Configuration loaded: {:?}", Config::current());
+    log::info!("Connected to {}", state.backend.get_node_id().await?);
+
+    loop {
+        let config = Config::current();
+        if config.dynamic_fees {
+            log::info!("Initiating dynamic fee adjustment");
+            if let Err(err) = set_channel_fees(config.clone(), &state).await {
+                log::warn!("Error in set channel fees.  Proceeding: {:?}", err);
+            }
+        } else {
+            log::warn!("`dynamic-fees` is not enabled, nothing to do");
+        }
+        let interval = config.dynamic_fee_update_interval as u64;
+        state.status.schedule_next(ceebalancer::now() + interval);
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
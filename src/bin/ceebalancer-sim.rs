@@ -0,0 +1,91 @@
+//! Backtests candidate configurations against recorded or synthetic history,
+//! without touching a node.  Candidates are a JSON array of plugin options,
+//! each with a `name`, e.g.
+//!
+//! ```json
+//! [
+//!     { "name": "current" },
+//!     { "name": "steep", "dynamic-fee-curve": "exponential", "dynamic-fee-max": 3000 }
+//! ]
+//! ```
+//!
+//! Replay a store file and the output of `lightning-cli listforwards`:
+//!
+//! ```text
+//! ceebalancer-sim --configs candidates.json --history ceebalancer.jsonl --forwards forwards.json
+//! ```
+//!
+//! or a seeded random walk with `--synthetic <steps>`.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{anyhow, Error};
+
+use ceebalancer::settings::{option_value, parse_configuration};
+use ceebalancer::simulator::{read_history, simulate, synthetic};
+use ceebalancer::wire;
+
+const USAGE: &str = "Usage: ceebalancer-sim --configs <file> \
+    (--history <store file> [--forwards <listforwards file>] | --synthetic <steps> [--seed <n>])";
+
+fn parse_args() -> Result<HashMap<String, String>, Error> {
+    let mut args = std::env::args().skip(1);
+    let mut parsed = HashMap::new();
+    while let Some(flag) = args.next() {
+        let name = flag
+            .strip_prefix("--")
+            .ok_or_else(|| anyhow!("Unexpected argument '{}'. {}", flag, USAGE))?;
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("Missing value for {}. {}", flag, USAGE))?;
+        parsed.insert(name.to_string(), value);
+    }
+    Ok(parsed)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let args = parse_args()?;
+    let configs_path = args.get("configs").ok_or_else(|| anyhow!(USAGE))?;
+    let candidates: Vec<serde_json::Value> =
+        serde_json::from_str(&std::fs::read_to_string(configs_path)?)?;
+
+    let (history, forwards) = match (args.get("history"), args.get("synthetic")) {
+        (Some(path), None) => {
+            let history = read_history(BufReader::new(File::open(path)?))?;
+            let forwards = match args.get("forwards") {
+                Some(path) => {
+                    let res: wire::ListForwardsResponseForwards =
+                        serde_json::from_str(&std::fs::read_to_string(path)?)?;
+                    res.forwards
+                }
+                None => vec![],
+            };
+            (history, forwards)
+        }
+        (None, Some(steps)) => {
+            let seed = match args.get("seed") {
+                Some(seed) => seed.parse()?,
+                None => 0,
+            };
+            // One step per default update interval
+            synthetic(steps.parse()?, 7200, seed)
+        }
+        _ => return Err(anyhow!(USAGE)),
+    };
+
+    let mut results = vec![];
+    for (i, candidate) in candidates.iter().enumerate() {
+        let name = candidate
+            .get("name")
+            .and_then(|n| n.as_str())
+            .map(|n| n.to_string())
+            .unwrap_or_else(|| format!("config-{}", i));
+        let config = parse_configuration(|option| candidate.get(option).and_then(option_value))
+            .map_err(|e| anyhow!("{}: {}", name, e))?;
+        results.push(simulate(&name, &config, &history, &forwards).await?);
+    }
+    println!("{}", serde_json::to_string_pretty(&results)?);
+    Ok(())
+}
@@ -0,0 +1,318 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::analytics;
+use crate::store::ChannelHistory;
+use crate::{now, wire, Config, FeeCurve, LightningBackend, PluginState};
+
+/// Share of a peer's forwarded volume that has to go one way before the
+/// traffic counts as one-sided.
+const LOPSIDED: f64 = 0.5;
+
+/// Share of a quiet peer's capacity the balance has to move for it not to
+/// count as dormant, e.g. because of payments rather than forwards.
+const MOVED: f64 = 0.05;
+
+/// What a peer does with the liquidity we give it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerClass {
+    /// Takes liquidity: we mostly forward out to it (e.g. an exchange or a
+    /// merchant).
+    Sink,
+    /// Gives liquidity: we mostly forward what it sends us.
+    Source,
+    /// Traffic goes both ways.
+    Router,
+    /// Nothing moved within the window.
+    Dormant,
+}
+
+impl FromStr for PeerClass {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<PeerClass, Error> {
+        match s.trim().to_lowercase().as_str() {
+            "sink" => Ok(PeerClass::Sink),
+            "source" => Ok(PeerClass::Source),
+            "router" => Ok(PeerClass::Router),
+            "dormant" => Ok(PeerClass::Dormant),
+            _ => Err(anyhow!("Unknown peer class: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for PeerClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PeerClass::Sink => "sink",
+            PeerClass::Source => "source",
+            PeerClass::Router => "router",
+            PeerClass::Dormant => "dormant",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Parses `<class>=<curve>;...`, e.g. `sink=exponential:3;router=sigmoid`.
+/// Semicolons because piecewise curves already use commas.
+pub fn parse_class_curves(s: &str) -> Result<BTreeMap<PeerClass, FeeCurve>, String> {
+    s.split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (class, curve) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected <class>=<curve>, got {:?}", entry))?;
+            let class: PeerClass = class.parse().map_err(|e: Error| e.to_string())?;
+            let curve: FeeCurve = curve.parse().map_err(|e: Error| e.to_string())?;
+            Ok((class, curve))
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Classification {
+    pub class: PeerClass,
+    pub channels: Vec<String>,
+    /// Settled volume forwarded in from the peer.
+    pub in_msat: u64,
+    /// Settled volume forwarded out to the peer.
+    pub out_msat: u64,
+    /// `(out - in) / (out + in)`, from -1.0 (all in) to 1.0 (all out).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub net_flow: Option<f64>,
+    /// How much our share of the peer's channels changed over the window,
+    /// from the recorded snapshots.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance_trend: Option<f64>,
+}
+
+/// Change in our share of the channels' capacity between the first snapshot
+/// within the window and the latest one.
+fn balance_trend(
+    short_channel_ids: &[String],
+    histories: &HashMap<String, ChannelHistory>,
+    since: u64,
+) -> Option<f64> {
+    let (moved, capacity) = short_channel_ids
+        .iter()
+        .filter_map(|id| {
+            let mut recent = histories
+                .get(id)?
+                .snapshots
+                .iter()
+                .filter(|s| s.timestamp >= since);
+            let first = recent.next()?;
+            let last = recent.last().unwrap_or(first);
+            Some((
+                last.our_amount_msat as i64 - first.our_amount_msat as i64,
+                last.amount_msat,
+            ))
+        })
+        .fold((0i64, 0u64), |(moved, capacity), (m, c)| {
+            (moved + m, capacity + c)
+        });
+    if capacity == 0 {
+        None
+    } else {
+        Some(moved as f64 / capacity as f64)
+    }
+}
+
+fn class_of(net_flow: Option<f64>, balance_trend: Option<f64>) -> PeerClass {
+    match (net_flow, balance_trend) {
+        (Some(net), _) if net >= LOPSIDED => PeerClass::Sink,
+        (Some(net), _) if net <= -LOPSIDED => PeerClass::Source,
+        (Some(_), _) => PeerClass::Router,
+        // No forwards, but the balance still moved
+        (None, Some(trend)) if trend <= -MOVED => PeerClass::Sink,
+        (None, Some(trend)) if trend >= MOVED => PeerClass::Source,
+        (None, _) => PeerClass::Dormant,
+    }
+}
+
+/// Labels each peer from the forwards over its channels within `window`
+/// seconds before `now`, falling back to the balance trend for peers we
+/// haven't forwarded for.
+pub fn classify(
+    channels: &[wire::Channel],
+    forwards: &[wire::Forward],
+    histories: &HashMap<String, ChannelHistory>,
+    now: u64,
+    window: u64,
+) -> BTreeMap<String, Classification> {
+    let flows = analytics::aggregate(forwards, now, &[window])
+        .pop()
+        .map(|report| report.channels)
+        .unwrap_or_default();
+    let mut peers: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for channel in channels {
+        if let Some(id) = &channel.short_channel_id {
+            peers
+                .entry(channel.peer_id.clone())
+                .or_default()
+                .push(id.clone());
+        }
+    }
+    peers
+        .into_iter()
+        .map(|(peer_id, short_channel_ids)| {
+            let (in_msat, out_msat) = short_channel_ids
+                .iter()
+                .filter_map(|id| flows.get(id))
+                .fold((0, 0), |(i, o), flow| (i + flow.in_msat, o + flow.out_msat));
+            let volume = in_msat + out_msat;
+            let net_flow = if volume > 0 {
+                Some((out_msat as f64 - in_msat as f64) / volume as f64)
+            } else {
+                None
+            };
+            let balance_trend =
+                balance_trend(&short_channel_ids, histories, now.saturating_sub(window));
+            let classification = Classification {
+                class: class_of(net_flow, balance_trend),
+                channels: short_channel_ids,
+                in_msat,
+                out_msat,
+                net_flow,
+                balance_trend,
+            };
+            (peer_id, classification)
+        })
+        .collect()
+}
+
+/// Everything `ceebalancer-classify` returns.
+pub async fn classification_report<B: LightningBackend>(
+    state: &PluginState<B>,
+    window: u64,
+) -> Result<BTreeMap<String, Classification>, Error> {
+    let channels = state.backend.list_channels().await?;
+    let forwards = state.backend.list_forwards().await?;
+    Ok(classify(
+        &channels,
+        &forwards,
+        &state.store.channels(),
+        now(),
+        window,
+    ))
+}
+
+/// Each peer's class, when `dynamic_fee_class_curves` has a use for it.  If
+/// the peers can't be classified, channels keep the global curve.
+pub async fn peer_classes<B: LightningBackend>(
+    config: &Config,
+    state: &PluginState<B>,
+) -> HashMap<String, PeerClass> {
+    if config.dynamic_fee_class_curves.is_empty() {
+        return HashMap::new();
+    }
+    let window = config.dynamic_fee_classify_window.max(1) as u64;
+    match classification_report(state, window).await {
+        Ok(report) => report
+            .into_iter()
+            .map(|(peer_id, classification)| (peer_id, classification.class))
+            .collect(),
+        Err(e) => {
+            log::warn!("Error classifying peers: {:?}", e);
+            HashMap::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primitives::Amount;
+    use crate::store::Snapshot;
+
+    fn channel(short_channel_id: &str, peer_id: &str) -> wire::Channel {
+        wire::Channel {
+            peer_id: peer_id.to_string(),
+            short_channel_id: Some(short_channel_id.to_string()),
+            ..crate::test_channel(1_000_000, 500_000)
+        }
+    }
+
+    fn forward(in_channel: &str, out_channel: &str, msat: u64) -> wire::Forward {
+        wire::Forward {
+            in_channel: in_channel.to_string(),
+            out_channel: Some(out_channel.to_string()),
+            in_msat: Some(Amount::from_msat(msat)),
+            out_msat: Some(Amount::from_msat(msat)),
+            fee_msat: Some(Amount::from_msat(0)),
+            status: wire::ForwardStatus::Settled,
+            received_time: 950.0,
+            resolved_time: None,
+        }
+    }
+
+    fn history(balances: &[(u64, u64)]) -> ChannelHistory {
+        ChannelHistory {
+            snapshots: balances
+                .iter()
+                .map(|&(timestamp, ours)| Snapshot {
+                    timestamp,
+                    our_amount_msat: ours,
+                    amount_msat: 1_000_000,
+                })
+                .collect(),
+            last_applied: None,
+            pid: None,
+        }
+    }
+
+    #[test]
+    fn peers_are_labelled_by_flow_then_balance() {
+        let channels = vec![
+            channel("1x1x1", "02exchange"),
+            channel("2x2x2", "02wallet"),
+            channel("3x3x3", "02router"),
+            channel("4x4x4", "02merchant"),
+            channel("5x5x5", "02idle"),
+        ];
+        let forwards = vec![
+            forward("2x2x2", "1x1x1", 9000),
+            forward("3x3x3", "1x1x1", 1000),
+            forward("1x1x1", "3x3x3", 500),
+        ];
+        let histories = HashMap::from([
+            (
+                "4x4x4".to_string(),
+                history(&[(0, 900_000), (500, 800_000), (990, 600_000)]),
+            ),
+            (
+                "5x5x5".to_string(),
+                history(&[(500, 500_000), (990, 510_000)]),
+            ),
+        ]);
+
+        let peers = classify(&channels, &forwards, &histories, 1000, 600);
+
+        assert_eq!(peers["02exchange"].class, PeerClass::Sink);
+        assert_eq!(peers["02exchange"].out_msat, 10_000);
+        assert_eq!(peers["02wallet"].class, PeerClass::Source);
+        assert_eq!(peers["02router"].class, PeerClass::Router);
+        assert_eq!(peers["02merchant"].class, PeerClass::Sink);
+        assert!((peers["02merchant"].balance_trend.unwrap() + 0.2).abs() < 1e-9);
+        assert_eq!(peers["02idle"].class, PeerClass::Dormant);
+    }
+
+    #[test]
+    fn class_curves_parse() {
+        let curves = parse_class_curves("sink=exponential:3; router=piecewise:0=0,1=500").unwrap();
+        assert_eq!(curves[&PeerClass::Sink], FeeCurve::Exponential(3.0));
+        assert_eq!(
+            curves[&PeerClass::Router],
+            FeeCurve::Piecewise(vec![(0.0, 0.0), (1.0, 500.0)])
+        );
+        assert!(parse_class_curves("").unwrap().is_empty());
+        assert!(parse_class_curves("whale=linear").is_err());
+        assert!(parse_class_curves("sink").is_err());
+    }
+}
@@ -1,70 +1,393 @@
 use cln_rpc::{model, ClnRpc, Request};
-use std::path::Path;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
 use anyhow::{anyhow, Error};
 
-use serde::{Deserialize, Serialize};
-
-use std::sync::{Arc, RwLock};
-
-use crate::primitives;
+use crate::backend::LightningBackend;
+use crate::primitives::Amount;
 use crate::wire;
-use crate::Config;
+use crate::ChannelPolicy;
 
-pub async fn get_info() -> Result<String, Error> {
-    let req = Request::Getinfo(model::GetinfoRequest {});
+/// Core Lightning, reached over its JSON-RPC unix socket.  One connection is
+/// opened on first use and shared by every clone; it's re-opened if it
+/// breaks.  Calls that return the whole history or wait on the network get
+/// a connection of their own, so they don't hold up the rest.
+#[derive(Clone, Debug)]
+pub struct ClnBackend {
+    connection: Arc<Mutex<Connection>>,
+}
 
-    Ok(call(req).await?)
+struct Connection {
+    rpc_path: PathBuf,
+    rpc: Option<ClnRpc>,
 }
 
-pub async fn list_channels() -> Result<Vec<wire::Channel>, Error> {
-    let req = Request::ListFunds(model::ListfundsRequest { spent: Some(false) });
-    let res = call(req).await?;
-    log::debug!("{}", &res);
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("rpc_path", &self.rpc_path)
+            .field("connected", &self.rpc.is_some())
+            .finish()
+    }
+}
 
-    let de: wire::ListFundsResponse = serde_json::from_str(&res).unwrap();
+impl ClnBackend {
+    /// Talks to the `lightning-rpc` socket in the current directory until
+    /// `set_rpc_path` says otherwise.
+    pub fn new() -> ClnBackend {
+        ClnBackend::with_rpc_path("lightning-rpc")
+    }
 
-    Ok(de.result.channels)
-}
+    pub fn with_rpc_path<P: AsRef<Path>>(rpc_path: P) -> ClnBackend {
+        ClnBackend {
+            connection: Arc::new(Mutex::new(Connection {
+                rpc_path: rpc_path.as_ref().to_path_buf(),
+                rpc: None,
+            })),
+        }
+    }
 
-pub async fn onchain_balance() -> Result<u64, Error> {
-    let req = Request::ListFunds(model::ListfundsRequest { spent: Some(false) });
-    let res = call(req).await?;
-    let de: wire::ListFundsResponse = serde_json::from_str(&res).unwrap();
+    /// Points every clone at the socket lightningd told us about in `init`,
+    /// dropping any connection to the old one.
+    pub async fn set_rpc_path<P: AsRef<Path>>(&self, rpc_path: P) {
+        let mut connection = self.connection.lock().await;
+        connection.rpc_path = rpc_path.as_ref().to_path_buf();
+        connection.rpc = None;
+    }
+
+    async fn call(&self, request: Request) -> Result<String, Error> {
+        let mut connection = self.connection.lock().await;
+        let mut retried = false;
+        let response = loop {
+            if connection.rpc.is_none() {
+                log::debug!("Connecting to {:?}", connection.rpc_path);
+                connection.rpc = Some(ClnRpc::new(&connection.rpc_path).await?);
+            }
+            let rpc = connection.rpc.as_mut().unwrap();
+            match rpc.call(request.clone()).await {
+                Ok(response) => break response,
+                // No code means it never got an answer from lightningd, so
+                // the socket is likely gone
+                Err(e) if e.code.is_none() && !retried => {
+                    log::warn!("RPC connection failed, reconnecting: {:?}", e);
+                    connection.rpc = None;
+                    retried = true;
+                }
+                Err(e) => {
+                    if e.code.is_none() {
+                        connection.rpc = None;
+                    }
+                    return Err(anyhow!("Error calling {:?}: {:?}", request, e));
+                }
+            }
+        };
+
+        Ok(serde_json::to_string_pretty(&response)?)
+    }
 
-    let mut total = 0;
-    for output in de.result.outputs {
-        total += output.amount_msat.msat();
+    /// Like `call`, but over a fresh connection that's closed afterwards.
+    /// lightningd answers each connection on its own, so a slow call here
+    /// doesn't queue everything else behind it on the shared one.
+    async fn call_alone(&self, request: Request) -> Result<String, Error> {
+        let rpc_path = self.connection.lock().await.rpc_path.clone();
+        let mut rpc = ClnRpc::new(&rpc_path).await?;
+        let response = rpc
+            .call(request.clone())
+            .await
+            .map_err(|e| anyhow!("Error calling {:?}: {:?}", request, e))?;
+
+        Ok(serde_json::to_string_pretty(&response)?)
     }
 
-    Ok(total)
+    /// Spendable balance of each channel, by short_channel_id.
+    async fn spendable(&self) -> Result<HashMap<String, Amount>, Error> {
+        let req = Request::ListPeers(model::ListpeersRequest {
+            id: None,
+            level: None,
+        });
+        let res = self.call_alone(req).await?;
+        let de: wire::ListPeersResponse = serde_json::from_str(&res)?;
+
+        Ok(de
+            .result
+            .peers
+            .into_iter()
+            .flat_map(|peer| peer.channels)
+            .filter_map(|c| Some((c.short_channel_id?, c.spendable_msat?)))
+            .collect())
+    }
 }
 
-pub async fn set_channel_fee(
-    short_channel_id: &String,
-    fee: u32,
-    htlc_max_msat: u64,
-) -> Result<(), Error> {
-    let req = Request::SetChannel(model::SetchannelRequest {
-        id: short_channel_id.to_string(),
-        feeppm: Some(fee),
-        feebase: None,
-        htlcmax: Some(cln_rpc::primitives::Amount::from_msat(htlc_max_msat)),
-        htlcmin: None,
-    });
-    let res = call(req).await?;
-    log::info!("Set channel: {:?}", res);
-
-    Ok(())
+impl Default for ClnBackend {
+    fn default() -> ClnBackend {
+        ClnBackend::new()
+    }
 }
 
-async fn call(request: Request) -> Result<String, Error> {
-    let path = Path::new("lightning-rpc");
-    let mut rpc = ClnRpc::new(path).await?;
-    let response = rpc
-        .call(request.clone())
-        .await
-        .map_err(|e| anyhow!("Error calling {:?}: {:?}", request, e))?;
+impl LightningBackend for ClnBackend {
+    async fn get_info(&self) -> Result<String, Error> {
+        let req = Request::Getinfo(model::GetinfoRequest {});
+
+        Ok(self.call(req).await?)
+    }
+
+    async fn get_node_id(&self) -> Result<String, Error> {
+        let res = self.get_info().await?;
+        let de: wire::GetInfoResponse = serde_json::from_str(&res)?;
+
+        Ok(de.result.id)
+    }
+
+    async fn list_channel_policies(
+        &self,
+        node_id: &str,
+    ) -> Result<HashMap<String, ChannelPolicy>, Error> {
+        let req = Request::ListChannels(model::ListchannelsRequest {
+            short_channel_id: None,
+            source: Some(node_id.to_string()),
+            destination: None,
+        });
+        let res = self.call(req).await?;
+        let de: wire::ListChannelsResponse = serde_json::from_str(&res)?;
+
+        Ok(de
+            .result
+            .channels
+            .into_iter()
+            .map(|c| {
+                let policy = ChannelPolicy {
+                    fee_ppm: c.fee_per_millionth,
+                    base_fee_msat: Some(c.base_fee_millisatoshi),
+                    htlc_min_msat: Some(c.htlc_minimum_msat.msat()),
+                    htlc_max_msat: c.htlc_maximum_msat.map(|a| a.msat()).unwrap_or_default(),
+                };
+                (c.short_channel_id, policy)
+            })
+            .collect())
+    }
+
+    async fn list_channels_to(&self, node_id: &str) -> Result<Vec<wire::ListChannel>, Error> {
+        let req = Request::ListChannels(model::ListchannelsRequest {
+            short_channel_id: None,
+            source: None,
+            destination: Some(node_id.to_string()),
+        });
+        let res = self.call(req).await?;
+        let de: wire::ListChannelsResponse = serde_json::from_str(&res)?;
+
+        Ok(de.result.channels)
+    }
+
+    async fn list_channels(&self) -> Result<Vec<wire::Channel>, Error> {
+        let req = Request::ListFunds(model::ListfundsRequest { spent: Some(false) });
+        let res = self.call(req).await?;
+        log::debug!("{}", &res);
+
+        let de: wire::ListFundsResponse = serde_json::from_str(&res)?;
+        let mut channels = de.result.channels;
+
+        // listfunds only has the total balance, the spendable amount comes
+        // from listpeers.  Without it htlc_max falls back to estimating the
+        // reserve.
+        match self.spendable().await {
+            Ok(spendable) => {
+                for channel in channels.iter_mut() {
+                    channel.spendable_msat = channel
+                        .short_channel_id
+                        .as_ref()
+                        .and_then(|id| spendable.get(id))
+                        .copied();
+                }
+            }
+            Err(e) => log::warn!("Error looking up spendable balances: {:?}", e),
+        }
+
+        Ok(channels)
+    }
+
+    async fn onchain_balance(&self) -> Result<u64, Error> {
+        let req = Request::ListFunds(model::ListfundsRequest { spent: Some(false) });
+        let res = self.call(req).await?;
+        let de: wire::ListFundsResponse = serde_json::from_str(&res)?;
+
+        let mut total = 0;
+        for output in de.result.outputs {
+            total += output.amount_msat.msat();
+        }
+
+        Ok(total)
+    }
+
+    async fn set_channel_policy(
+        &self,
+        short_channel_id: &str,
+        policy: &ChannelPolicy,
+    ) -> Result<(), Error> {
+        let req = Request::SetChannel(model::SetchannelRequest {
+            id: short_channel_id.to_string(),
+            feeppm: Some(policy.fee_ppm),
+            feebase: policy
+                .base_fee_msat
+                .map(cln_rpc::primitives::Amount::from_msat),
+            htlcmax: Some(cln_rpc::primitives::Amount::from_msat(policy.htlc_max_msat)),
+            htlcmin: policy
+                .htlc_min_msat
+                .map(cln_rpc::primitives::Amount::from_msat),
+        });
+        let res = self.call(req).await?;
+        log::info!("Set channel: {:?}", res);
+
+        Ok(())
+    }
 
-    Ok(serde_json::to_string_pretty(&response)?)
+    async fn list_forwards(&self) -> Result<Vec<wire::Forward>, Error> {
+        let req = Request::ListForwards(model::ListforwardsRequest {
+            status: None,
+            in_channel: None,
+            out_channel: None,
+        });
+        let res = self.call_alone(req).await?;
+        let de: wire::ListForwardsResponse = serde_json::from_str(&res)?;
+
+        Ok(de.result.forwards)
+    }
+
+    async fn list_sendpays(&self) -> Result<Vec<wire::SendPay>, Error> {
+        let req = Request::ListSendPays(model::ListsendpaysRequest {
+            bolt11: None,
+            payment_hash: None,
+            status: Some(model::ListsendpaysStatus::COMPLETE),
+        });
+        let res = self.call_alone(req).await?;
+        let de: wire::ListSendPaysResponse = serde_json::from_str(&res)?;
+
+        Ok(de.result.payments)
+    }
+
+    async fn get_invoice(&self, payment_hash: &str) -> Result<Option<wire::ListInvoice>, Error> {
+        let req = Request::ListInvoices(model::ListinvoicesRequest {
+            label: None,
+            invstring: None,
+            payment_hash: Some(payment_hash.to_string()),
+            offer_id: None,
+        });
+        let res = self.call(req).await?;
+        let de: wire::ListInvoicesResponse = serde_json::from_str(&res)?;
+
+        Ok(de.result.invoices.into_iter().next())
+    }
+
+    async fn get_channel(&self, short_channel_id: &str) -> Result<Vec<wire::ListChannel>, Error> {
+        let req = Request::ListChannels(model::ListchannelsRequest {
+            short_channel_id: Some(short_channel_id.to_string()),
+            source: None,
+            destination: None,
+        });
+        let res = self.call(req).await?;
+        let de: wire::ListChannelsResponse = serde_json::from_str(&res)?;
+
+        Ok(de.result.channels)
+    }
+
+    async fn get_route(
+        &self,
+        from: &str,
+        to: &str,
+        amount_msat: u64,
+        exclude: &[String],
+    ) -> Result<Vec<wire::RouteHop>, Error> {
+        let req = Request::GetRoute(model::GetrouteRequest {
+            id: to.to_string(),
+            amount_msat: cln_rpc::primitives::Amount::from_msat(amount_msat),
+            riskfactor: 10,
+            cltv: None,
+            fromid: Some(from.to_string()),
+            fuzzpercent: None,
+            exclude: Some(exclude.to_vec()),
+            maxhops: None,
+        });
+        let res = self.call(req).await?;
+        let de: wire::GetRouteResponse = serde_json::from_str(&res)?;
+
+        Ok(de.result.route)
+    }
+
+    async fn create_invoice(
+        &self,
+        amount_msat: u64,
+        label: &str,
+        description: &str,
+    ) -> Result<wire::Invoice, Error> {
+        let req = Request::Invoice(model::InvoiceRequest {
+            amount_msat: cln_rpc::primitives::AmountOrAny::Amount(
+                cln_rpc::primitives::Amount::from_msat(amount_msat),
+            ),
+            description: description.to_string(),
+            label: label.to_string(),
+            expiry: Some(600),
+            fallbacks: None,
+            preimage: None,
+            exposeprivatechannels: None,
+            cltv: None,
+            deschashonly: None,
+        });
+        let res = self.call(req).await?;
+        let de: wire::InvoiceResponse = serde_json::from_str(&res)?;
+
+        Ok(de.result)
+    }
+
+    async fn delete_invoice(&self, label: &str) -> Result<(), Error> {
+        let req = Request::DelInvoice(model::DelinvoiceRequest {
+            label: label.to_string(),
+            status: model::DelinvoiceStatus::UNPAID,
+            desconly: None,
+        });
+        self.call(req).await?;
+
+        Ok(())
+    }
+
+    async fn send_to_route(
+        &self,
+        route: &[wire::RouteHop],
+        invoice: &wire::Invoice,
+    ) -> Result<(), Error> {
+        let req = Request::SendPay(model::SendpayRequest {
+            route: route
+                .iter()
+                .map(|hop| model::SendpayRoute {
+                    id: hop.id.clone(),
+                    channel: hop.channel.clone(),
+                    delay: hop.delay as u16,
+                    amount_msat: cln_rpc::primitives::Amount::from_msat(hop.amount_msat.msat()),
+                })
+                .collect(),
+            payment_hash: invoice.payment_hash.clone(),
+            label: None,
+            amount_msat: None,
+            bolt11: None,
+            payment_secret: Some(invoice.payment_secret.clone()),
+            partid: None,
+            localofferid: None,
+            groupid: None,
+        });
+        self.call(req).await?;
+        // An error here is the payment failing somewhere along the route.
+        // It can take up to the timeout, so it gets a connection of its own
+        let req = Request::WaitSendPay(model::WaitsendpayRequest {
+            payment_hash: invoice.payment_hash.clone(),
+            timeout: Some(60),
+            partid: None,
+            groupid: None,
+        });
+        let res = self.call_alone(req).await?;
+        log::info!("Sent to route: {}", res);
+
+        Ok(())
+    }
 }
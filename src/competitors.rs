@@ -0,0 +1,163 @@
+use crate::{wire, Config};
+
+/// What the other nodes with channels to a peer charge to forward into it,
+/// i.e. the fees a payer could pay instead of ours to reach that peer.
+#[derive(Clone, Debug, Default)]
+pub struct Competition {
+    fees: Vec<u32>,
+}
+
+impl Competition {
+    /// Picks the competing channels out of `listchannels destination=<peer>`,
+    /// leaving out our own.
+    pub fn new(graph: &[wire::ListChannel], our_node_id: &str, peer_id: &str) -> Competition {
+        let mut competition = Competition::default();
+        competition.add(graph, peer_id, &[our_node_id]);
+        competition
+    }
+
+    /// Adds the active channels into `destination`, other than those from
+    /// the `excluded` nodes.  Disabled channels can't take the payment, so
+    /// what they charge doesn't matter.
+    pub fn add(&mut self, graph: &[wire::ListChannel], destination: &str, excluded: &[&str]) {
+        self.fees.extend(
+            graph
+                .iter()
+                .filter(|c| {
+                    c.active && c.destination == destination && !excluded.contains(&&*c.source)
+                })
+                .map(|c| c.fee_per_millionth),
+        );
+        self.fees.sort_unstable();
+    }
+
+    /// The nodes with an active channel into the peer, i.e. the peers near
+    /// it, leaving us out.
+    pub fn neighbours(
+        graph: &[wire::ListChannel],
+        our_node_id: &str,
+        peer_id: &str,
+    ) -> Vec<String> {
+        let mut neighbours: Vec<String> = graph
+            .iter()
+            .filter(|c| c.active && c.destination == peer_id && c.source != our_node_id)
+            .map(|c| c.source.clone())
+            .collect();
+        neighbours.sort();
+        neighbours.dedup();
+        neighbours
+    }
+
+    pub fn len(&self) -> usize {
+        self.fees.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fees.is_empty()
+    }
+
+    /// Nearest-rank percentile of the competing fees, `p` in [0, 1].
+    pub fn percentile(&self, p: f64) -> Option<u32> {
+        if self.fees.is_empty() {
+            return None;
+        }
+        let rank = (p * self.fees.len() as f64).ceil() as usize;
+        Some(self.fees[rank.clamp(1, self.fees.len()) - 1])
+    }
+}
+
+/// The most we'll charge given the competition, or `None` when there are too
+/// few competing channels to say what the market rate is.  Never below
+/// `dynamic_fee_min`.
+pub fn fee_cap(competition: &Competition, config: &Config) -> Option<u32> {
+    if (competition.len() as i64) < config.dynamic_fee_competitor_min_count {
+        return None;
+    }
+    competition
+        .percentile(config.dynamic_fee_competitor_percentile)
+        .map(|fee| fee.max(config.dynamic_fee_min.max(0) as u32))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primitives::Amount;
+
+    fn edge(source: &str, destination: &str, fee: u32) -> wire::ListChannel {
+        wire::ListChannel {
+            source: source.to_string(),
+            destination: destination.to_string(),
+            short_channel_id: "1x1x1".to_string(),
+            amount_msat: Amount::from_sat(1_000_000),
+            base_fee_millisatoshi: 1000,
+            fee_per_millionth: fee,
+            delay: 40,
+            htlc_minimum_msat: Amount::from_msat(0),
+            htlc_maximum_msat: None,
+            active: true,
+        }
+    }
+
+    #[test]
+    fn caps_at_the_percentile_of_other_nodes() {
+        let graph = vec![
+            edge("02us", "03peer", 5000),
+            edge("02a", "03peer", 100),
+            edge("02b", "03peer", 200),
+            edge("02c", "03peer", 300),
+            edge("02d", "03peer", 400),
+            edge("02e", "03other", 9000),
+        ];
+        let competition = Competition::new(&graph, "02us", "03peer");
+        assert_eq!(competition.len(), 4);
+
+        let config = Config {
+            dynamic_fee_competitor_percentile: 0.75,
+            dynamic_fee_competitor_min_count: 3,
+            ..Config::default()
+        };
+        assert_eq!(fee_cap(&competition, &config), Some(300));
+
+        let floor = Config {
+            dynamic_fee_min: 350,
+            ..config.clone()
+        };
+        assert_eq!(fee_cap(&competition, &floor), Some(350));
+
+        let too_few = Config {
+            dynamic_fee_competitor_min_count: 5,
+            ..config
+        };
+        assert_eq!(fee_cap(&competition, &too_few), None);
+    }
+
+    #[test]
+    fn disabled_channels_dont_compete() {
+        let graph = vec![
+            edge("02a", "03peer", 100),
+            wire::ListChannel {
+                active: false,
+                ..edge("02b", "03peer", 200)
+            },
+        ];
+        let competition = Competition::new(&graph, "02us", "03peer");
+        assert_eq!(competition.len(), 1);
+        assert_eq!(
+            Competition::neighbours(&graph, "02us", "03peer"),
+            vec!["02a".to_string()]
+        );
+    }
+
+    #[test]
+    fn peers_near_it_add_to_the_competition() {
+        let near = vec![
+            edge("02a", "03near", 300),
+            edge("03peer", "03near", 50),
+            edge("02us", "03near", 5000),
+        ];
+        let mut competition = Competition::new(&[edge("02a", "03peer", 100)], "02us", "03peer");
+        competition.add(&near, "03near", &["02us", "03peer"]);
+        assert_eq!(competition.len(), 2);
+        assert_eq!(competition.percentile(1.0), Some(300));
+    }
+}
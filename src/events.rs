@@ -0,0 +1,178 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::wire::{ForwardEvent, ForwardStatus};
+
+/// Collects the channels touched by forwards so a burst of payments results
+/// in a single re-evaluation of each channel.
+#[derive(Clone, Debug, Default)]
+pub struct Debouncer {
+    pending: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Debouncer {
+    pub fn new() -> Debouncer {
+        Debouncer::default()
+    }
+
+    /// Queues the channels for re-evaluation.  Returns true when this opened a
+    /// new window, in which case the caller is responsible for scheduling the
+    /// flush.
+    pub fn push<I>(&self, short_channel_ids: I) -> bool
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut pending = self.pending.lock().unwrap();
+        let was_empty = pending.is_empty();
+        pending.extend(short_channel_ids);
+        was_empty && !pending.is_empty()
+    }
+
+    pub fn drain(&self) -> Vec<String> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.drain().collect()
+    }
+}
+
+/// Remembers when dust-sized HTLCs went through each channel, so channels
+/// whose HTLC slots are being eaten by tiny payments can be spotted.
+#[derive(Clone, Debug, Default)]
+pub struct DustTracker {
+    seen: Arc<Mutex<HashMap<String, VecDeque<u64>>>>,
+}
+
+impl DustTracker {
+    pub fn new() -> DustTracker {
+        DustTracker::default()
+    }
+
+    /// Records the forward against its outgoing channel if it was resolved
+    /// and smaller than `dust_msat`.  That's the channel whose fee and HTLC
+    /// limits the dust went through.
+    pub fn record(&self, event: &ForwardEvent, dust_msat: u64, timestamp: u64) {
+        if event.status == ForwardStatus::Offered {
+            return;
+        }
+        match event.in_msat {
+            Some(amount) if amount.msat() < dust_msat => {}
+            _ => return,
+        }
+        if let Some(out_channel) = &event.out_channel {
+            self.seen
+                .lock()
+                .unwrap()
+                .entry(out_channel.clone())
+                .or_default()
+                .push_back(timestamp);
+        }
+    }
+
+    /// Number of dust HTLCs through the channel since `since`.  Older entries
+    /// are dropped.
+    pub fn count(&self, short_channel_id: &str, since: u64) -> usize {
+        let mut seen = self.seen.lock().unwrap();
+        match seen.get_mut(short_channel_id) {
+            Some(timestamps) => {
+                while timestamps.front().map_or(false, |t| *t < since) {
+                    timestamps.pop_front();
+                }
+                timestamps.len()
+            }
+            None => 0,
+        }
+    }
+}
+
+/// Channels whose balance may have moved as a result of the forward.
+pub fn affected_channels(event: &ForwardEvent) -> Vec<String> {
+    match event.status {
+        ForwardStatus::Settled | ForwardStatus::Failed => {
+            let mut channels = vec![event.in_channel.clone()];
+            if let Some(out_channel) = &event.out_channel {
+                channels.push(out_channel.clone());
+            }
+            channels
+        }
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::wire;
+    use serde_json::json;
+
+    #[test]
+    fn settled_forward_touches_both_channels() {
+        let j = json!({
+            "forward_event": {
+                "payment_hash": "f5a6a059a25d1e329d9b094aeeec8c2191ca037d3f5b0662e21ae850debe8ea2",
+                "in_channel": "103x2x1",
+                "out_channel": "103x1x1",
+                "in_msat": "100001001msat",
+                "out_msat": "100000000msat",
+                "fee_msat": "1001msat",
+                "status": "settled",
+                "received_time": 1560696342.368,
+                "resolved_time": 1560696342.556
+            }
+        });
+        let de: wire::ForwardEventNotification = serde_json::from_value(j).unwrap();
+        assert_eq!(
+            affected_channels(&de.forward_event),
+            vec!["103x2x1".to_string(), "103x1x1".to_string()]
+        );
+    }
+
+    #[test]
+    fn offered_forward_is_ignored() {
+        let j = json!({
+            "forward_event": {
+                "in_channel": "103x2x1",
+                "out_channel": "110x1x0",
+                "in_msat": "100001001msat",
+                "status": "offered",
+                "received_time": 1560696342.368
+            }
+        });
+        let de: wire::ForwardEventNotification = serde_json::from_value(j).unwrap();
+        assert!(affected_channels(&de.forward_event).is_empty());
+    }
+
+    #[test]
+    fn dust_tracker_counts_small_resolved_forwards() {
+        let event = |in_msat: &str, status: &str| -> wire::ForwardEvent {
+            serde_json::from_value(json!({
+                "in_channel": "103x2x1",
+                "out_channel": "103x1x1",
+                "in_msat": in_msat,
+                "status": status
+            }))
+            .unwrap()
+        };
+        let tracker = DustTracker::new();
+        tracker.record(&event("1000msat", "settled"), 10_000, 100);
+        tracker.record(&event("2000msat", "failed"), 10_000, 200);
+        tracker.record(&event("1000msat", "offered"), 10_000, 200);
+        tracker.record(&event("50000msat", "settled"), 10_000, 200);
+
+        assert_eq!(tracker.count("103x1x1", 150), 1);
+        assert_eq!(tracker.count("103x1x1", 0), 1);
+        // The incoming channel isn't counted
+        assert_eq!(tracker.count("103x2x1", 0), 0);
+        assert_eq!(tracker.count("999x1x1", 0), 0);
+    }
+
+    #[test]
+    fn debouncer_opens_one_window_per_burst() {
+        let debouncer = Debouncer::new();
+        assert!(debouncer.push(vec!["1x1x1".to_string()]));
+        assert!(!debouncer.push(vec!["1x1x1".to_string(), "2x2x2".to_string()]));
+
+        let mut drained = debouncer.drain();
+        drained.sort();
+        assert_eq!(drained, vec!["1x1x1".to_string(), "2x2x2".to_string()]);
+        assert!(debouncer.push(vec!["2x2x2".to_string()]));
+    }
+}
@@ -0,0 +1,306 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::Config;
+
+/// Maps the remote share of a channel's balance (0.0 = all ours, 1.0 = all
+/// theirs) onto a fee rate in ppm.
+pub trait FeeStrategy {
+    fn fee(&self, proportion: f64, config: &Config) -> f64;
+}
+
+/// Clamped ramp from `dynamic_fee_min` to `dynamic_fee_max` between
+/// `dynamic_fee_threshold` and `1 - dynamic_fee_threshold`.
+pub struct Linear;
+
+/// S-shaped ramp over the same window as `Linear`; `steepness` controls how
+/// sharply the fee moves around the midpoint.
+pub struct Sigmoid {
+    pub steepness: f64,
+}
+
+/// Ramp that stays cheap for most of the window and climbs quickly as the
+/// channel approaches depletion.
+pub struct Exponential {
+    pub exponent: f64,
+}
+
+/// User supplied (proportion, fee) points, interpolated linearly.  Fees
+/// outside the first and last point are held flat.
+pub struct Piecewise {
+    pub points: Vec<(f64, f64)>,
+}
+
+/// Where `proportion` falls between the thresholds, from 0.0 at or below
+/// `dynamic_fee_threshold` to 1.0 at or above `1 - dynamic_fee_threshold`.
+pub fn balance_position(proportion: f64, config: &Config) -> f64 {
+    let min_threshold_ratio: f64 = config.dynamic_fee_threshold as f64;
+    let max_threshold_ratio: f64 = 1.0 - config.dynamic_fee_threshold as f64;
+
+    if proportion <= min_threshold_ratio {
+        0.0
+    } else if proportion >= max_threshold_ratio {
+        1.0
+    } else {
+        let nom = proportion - min_threshold_ratio;
+        let denom = max_threshold_ratio - min_threshold_ratio;
+        nom / denom
+    }
+}
+
+/// Stretches the remote share so the channel's target balance lands on 0.5,
+/// the middle of every curve.  `dynamic_fee_target_ratio` is the local share
+/// we'd like, so e.g. 0.8 makes 20% remote the balanced point.
+pub fn recentre(proportion: f64, config: &Config) -> f64 {
+    let target = (1.0 - config.dynamic_fee_target_ratio).clamp(f64::EPSILON, 1.0 - f64::EPSILON);
+    if proportion <= target {
+        0.5 * proportion / target
+    } else {
+        0.5 + 0.5 * (proportion - target) / (1.0 - target)
+    }
+}
+
+fn scale(position: f64, config: &Config) -> f64 {
+    let max: f64 = config.dynamic_fee_max as f64;
+    let min: f64 = config.dynamic_fee_min as f64;
+    (position * (max - min)) + min
+}
+
+impl FeeStrategy for Linear {
+    fn fee(&self, proportion: f64, config: &Config) -> f64 {
+        scale(balance_position(proportion, config), config)
+    }
+}
+
+impl FeeStrategy for Sigmoid {
+    fn fee(&self, proportion: f64, config: &Config) -> f64 {
+        let x = balance_position(proportion, config);
+        let logistic = |x: f64| 1.0 / (1.0 + (-self.steepness * (x - 0.5)).exp());
+        // Rescale so the curve still reaches min and max at the window edges
+        let low = logistic(0.0);
+        let high = logistic(1.0);
+        scale((logistic(x) - low) / (high - low), config)
+    }
+}
+
+impl FeeStrategy for Exponential {
+    fn fee(&self, proportion: f64, config: &Config) -> f64 {
+        let x = balance_position(proportion, config);
+        let position = ((self.exponent * x).exp() - 1.0) / (self.exponent.exp() - 1.0);
+        scale(position, config)
+    }
+}
+
+impl FeeStrategy for Piecewise {
+    fn fee(&self, proportion: f64, config: &Config) -> f64 {
+        let (first, last) = match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Linear.fee(proportion, config),
+        };
+        if proportion <= first.0 {
+            return first.1;
+        }
+        if proportion >= last.0 {
+            return last.1;
+        }
+        for pair in self.points.windows(2) {
+            let (x0, y0) = pair[0];
+            let (x1, y1) = pair[1];
+            if proportion <= x1 {
+                return y0 + (proportion - x0) / (x1 - x0) * (y1 - y0);
+            }
+        }
+        last.1
+    }
+}
+
+/// The curve selected through the `dynamic-fee-curve` option.
+///
+/// Parsed from `linear`, `sigmoid[:steepness]`, `exponential[:exponent]` or
+/// `piecewise:<proportion>=<fee>,...`, e.g. `piecewise:0.2=0,0.5=100,0.8=1000`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum FeeCurve {
+    #[default]
+    Linear,
+    Sigmoid(f64),
+    Exponential(f64),
+    Piecewise(Vec<(f64, f64)>),
+}
+
+impl FeeStrategy for FeeCurve {
+    fn fee(&self, proportion: f64, config: &Config) -> f64 {
+        match self {
+            FeeCurve::Linear => Linear.fee(proportion, config),
+            FeeCurve::Sigmoid(steepness) => Sigmoid {
+                steepness: *steepness,
+            }
+            .fee(proportion, config),
+            FeeCurve::Exponential(exponent) => Exponential {
+                exponent: *exponent,
+            }
+            .fee(proportion, config),
+            FeeCurve::Piecewise(points) => Piecewise {
+                points: points.clone(),
+            }
+            .fee(proportion, config),
+        }
+    }
+}
+
+impl FromStr for FeeCurve {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<FeeCurve, Error> {
+        let (name, arg) = match s.trim().split_once(':') {
+            Some((name, arg)) => (name, Some(arg)),
+            None => (s.trim(), None),
+        };
+        let parameter = |default: f64| -> Result<f64, Error> {
+            let value = match arg {
+                Some(a) => a
+                    .parse::<f64>()
+                    .map_err(|_| anyhow!("Invalid parameter for {} curve: {}", name, a))?,
+                None => default,
+            };
+            if !value.is_finite() || value <= 0.0 {
+                return Err(anyhow!("{} curve parameter must be positive", name));
+            }
+            Ok(value)
+        };
+
+        match name.to_lowercase().as_str() {
+            "linear" => Ok(FeeCurve::Linear),
+            "sigmoid" => Ok(FeeCurve::Sigmoid(parameter(10.0)?)),
+            "exponential" => Ok(FeeCurve::Exponential(parameter(3.0)?)),
+            "piecewise" => {
+                let arg = arg.ok_or_else(|| anyhow!("piecewise curve requires points"))?;
+                let mut points = vec![];
+                for point in arg.split(',') {
+                    let (x, y) = point
+                        .split_once('=')
+                        .ok_or_else(|| anyhow!("Invalid piecewise point: {}", point))?;
+                    let x: f64 = x
+                        .trim()
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid piecewise proportion: {}", x))?;
+                    let y: f64 = y
+                        .trim()
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid piecewise fee: {}", y))?;
+                    if !(0.0..=1.0).contains(&x) {
+                        return Err(anyhow!("Piecewise proportion out of range: {}", x));
+                    }
+                    points.push((x, y));
+                }
+                points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+                Ok(FeeCurve::Piecewise(points))
+            }
+            _ => Err(anyhow!("Unknown fee curve: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for FeeCurve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FeeCurve::Linear => write!(f, "linear"),
+            FeeCurve::Sigmoid(steepness) => write!(f, "sigmoid:{}", steepness),
+            FeeCurve::Exponential(exponent) => write!(f, "exponential:{}", exponent),
+            FeeCurve::Piecewise(points) => {
+                let points: Vec<String> =
+                    points.iter().map(|(x, y)| format!("{}={}", x, y)).collect();
+                write!(f, "piecewise:{}", points.join(","))
+            }
+        }
+    }
+}
+
+impl Serialize for FeeCurve {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for FeeCurve {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let s: String = Deserialize::deserialize(deserializer)?;
+        s.parse().map_err(|e| Error::custom(format!("{}", e)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            dynamic_fee_min: 0,
+            dynamic_fee_max: 1000,
+            dynamic_fee_threshold: 0.2,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn curves_reach_min_and_max_at_thresholds() {
+        let config = config();
+        for curve in ["linear", "sigmoid", "exponential:2"] {
+            let curve: FeeCurve = curve.parse().unwrap();
+            assert_eq!(curve.fee(0.1, &config).round(), 0.0);
+            assert_eq!(curve.fee(0.9, &config).round(), 1000.0);
+        }
+    }
+
+    #[test]
+    fn exponential_stays_below_linear() {
+        let config = config();
+        let exponential: FeeCurve = "exponential:3".parse().unwrap();
+        assert!(exponential.fee(0.5, &config) < FeeCurve::Linear.fee(0.5, &config));
+    }
+
+    #[test]
+    fn piecewise_interpolates_points() {
+        let config = config();
+        let curve: FeeCurve = "piecewise:0.8=1000,0.0=0,0.5=100".parse().unwrap();
+        assert_eq!(curve.fee(0.25, &config), 50.0);
+        assert_eq!(curve.fee(0.65, &config), 550.0);
+        assert_eq!(curve.fee(1.0, &config), 1000.0);
+        assert_eq!(curve.to_string(), "piecewise:0=0,0.5=100,0.8=1000");
+    }
+
+    #[test]
+    fn target_ratio_moves_the_middle() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        let config = config();
+        assert!(close(recentre(0.3, &config), 0.3));
+
+        let sink = Config {
+            dynamic_fee_target_ratio: 0.8,
+            ..config
+        };
+        assert!(close(recentre(0.2, &sink), 0.5));
+        assert!(close(recentre(0.1, &sink), 0.25));
+        assert!(close(recentre(0.6, &sink), 0.75));
+        assert!(close(recentre(1.0, &sink), 1.0));
+        let fee = FeeCurve::Linear.fee(recentre(0.2, &sink), &sink);
+        assert_eq!(fee.round(), 500.0);
+    }
+
+    #[test]
+    fn rejects_invalid_curves() {
+        assert!("cubic".parse::<FeeCurve>().is_err());
+        assert!("sigmoid:-1".parse::<FeeCurve>().is_err());
+        assert!("piecewise".parse::<FeeCurve>().is_err());
+        assert!("piecewise:1.5=10".parse::<FeeCurve>().is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Buckets htlc_max is rounded to unless `dynamic-htlc-max-ladder` says
+/// otherwise.
+pub const DEFAULT_LADDER: [u64; 18] = [
+    1_000,
+    100_000,
+    250_000,
+    1_000_000,
+    10_000_000,
+    50_000_000,
+    100_000_000,
+    250_000_000,
+    500_000_000,
+    1_000_000_000,
+    2_000_000_000,
+    3_000_000_000,
+    4_000_000_000,
+    5_000_000_000,
+    7_500_000_000,
+    10_000_000_000,
+    15_000_000_000,
+    20_000_000_000,
+];
+
+/// How the balance is snapped onto the ladder before the ratio is applied.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Rounding {
+    /// Largest bucket at or below the balance
+    #[default]
+    Down,
+    /// Closest bucket, which may be above the balance
+    Nearest,
+    /// Ignore the ladder and use the balance as is
+    None,
+}
+
+/// Which balance htlc_max is derived from.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Source {
+    /// `our_amount_msat` as reported by `listfunds`
+    #[default]
+    Total,
+    /// What the node says we can send, or failing that `our_amount_msat`
+    /// less an estimate of the channel reserve
+    Spendable,
+}
+
+impl Rounding {
+    /// Snaps `amount` onto an ascending `ladder`.  Amounts below the first
+    /// bucket are left alone.
+    pub fn apply(&self, amount: u64, ladder: &[u64]) -> u64 {
+        match self {
+            Rounding::Down => ladder
+                .iter()
+                .rev()
+                .find(|&x| &amount >= x)
+                .copied()
+                .unwrap_or(amount),
+            Rounding::Nearest => match ladder.first() {
+                Some(first) if amount >= *first => ladder
+                    .iter()
+                    .min_by_key(|&&x| (x as i128 - amount as i128).abs())
+                    .copied()
+                    .unwrap_or(amount),
+                _ => amount,
+            },
+            Rounding::None => amount,
+        }
+    }
+}
+
+impl FromStr for Rounding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Rounding, Error> {
+        match s.trim().to_lowercase().as_str() {
+            "down" => Ok(Rounding::Down),
+            "nearest" => Ok(Rounding::Nearest),
+            "none" => Ok(Rounding::None),
+            _ => Err(anyhow!("Unknown htlc_max rounding: {}", s)),
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Source, Error> {
+        match s.trim().to_lowercase().as_str() {
+            "total" => Ok(Source::Total),
+            "spendable" => Ok(Source::Spendable),
+            _ => Err(anyhow!("Unknown htlc_max source: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Rounding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rounding::Down => write!(f, "down"),
+            Rounding::Nearest => write!(f, "nearest"),
+            Rounding::None => write!(f, "none"),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Total => write!(f, "total"),
+            Source::Spendable => write!(f, "spendable"),
+        }
+    }
+}
+
+impl Serialize for Rounding {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Rounding {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let s: String = Deserialize::deserialize(deserializer)?;
+        s.parse().map_err(|e| Error::custom(format!("{}", e)))
+    }
+}
+
+impl Serialize for Source {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Source {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let s: String = Deserialize::deserialize(deserializer)?;
+        s.parse().map_err(|e| Error::custom(format!("{}", e)))
+    }
+}
+
+/// Parses a comma separated list of msat amounts into an ascending ladder.
+pub fn parse_ladder(s: &str) -> Result<Vec<u64>, Error> {
+    let mut ladder = s
+        .split(',')
+        .map(|x| {
+            x.trim()
+                .parse::<u64>()
+                .map_err(|_| anyhow!("Invalid htlc_max bucket: {}", x))
+        })
+        .collect::<Result<Vec<u64>, Error>>()?;
+    ladder.sort_unstable();
+    ladder.dedup();
+    Ok(ladder)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rounds_onto_ladder() {
+        let ladder = [1_000, 10_000, 100_000];
+        assert_eq!(Rounding::Down.apply(99_999, &ladder), 10_000);
+        assert_eq!(Rounding::Nearest.apply(99_999, &ladder), 100_000);
+        assert_eq!(Rounding::Nearest.apply(20_000, &ladder), 10_000);
+        assert_eq!(Rounding::Nearest.apply(500, &ladder), 500);
+        assert_eq!(Rounding::None.apply(99_999, &ladder), 99_999);
+        assert_eq!(Rounding::Down.apply(500, &ladder), 500);
+        assert_eq!(Rounding::Down.apply(500, &[]), 500);
+    }
+
+    #[test]
+    fn parses_ladder() {
+        assert_eq!(
+            parse_ladder("100000, 1000,100000").unwrap(),
+            vec![1_000, 100_000]
+        );
+        assert!(parse_ladder("1000,lots").is_err());
+    }
+}
@@ -2,128 +2,766 @@ use anyhow::{anyhow, Error};
 
 use serde::{Deserialize, Serialize};
 
+pub mod analytics;
+pub mod backend;
+pub mod classify;
 pub mod cln_client;
+pub mod competitors;
+pub mod events;
+pub mod fee_strategy;
+pub mod htlc_max;
+pub mod lnd_client;
+pub mod overrides;
+pub mod pid;
+pub mod plugin_state;
 pub mod primitives;
+pub mod privacy;
+pub mod rebalancer;
+pub mod refills;
+pub mod settings;
+pub mod simulator;
+pub mod status;
+pub mod store;
+pub mod targets;
+pub mod validation;
 pub mod wire;
 
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub use crate::cln_client::{get_info, list_channels, onchain_balance, set_channel_fee};
+pub use crate::backend::LightningBackend;
+pub use crate::cln_client::ClnBackend;
+pub use crate::fee_strategy::{FeeCurve, FeeStrategy};
+pub use crate::plugin_state::PluginState;
 
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug, Serialize)]
 pub struct Config {
     pub dynamic_fees: bool,
+    pub dynamic_fees_dry_run: bool,
     pub dynamic_fee_min: i64,
     pub dynamic_fee_max: i64,
     pub dynamic_fee_threshold: f32,
     pub dynamic_fee_width: i64,
     pub dynamic_fee_update_interval: i64,
+    pub dynamic_fee_curve: FeeCurve,
+    pub dynamic_fee_target_ratio: f64,
+    pub dynamic_fee_target_learn_window: i64,
+    pub dynamic_fee_classify_window: i64,
+    pub dynamic_fee_class_curves: BTreeMap<classify::PeerClass, FeeCurve>,
+    pub dynamic_fee_mode: pid::FeeMode,
+    pub dynamic_fee_pid_kp: i64,
+    pub dynamic_fee_pid_ki: i64,
+    pub dynamic_fee_pid_kd: i64,
+    pub dynamic_fee_forward_debounce: i64,
+    pub dynamic_fee_deadband: i64,
+    pub dynamic_fee_htlc_max_deadband: f32,
+    pub dynamic_fee_hysteresis: f32,
+    pub dynamic_base_fee: bool,
+    pub dynamic_base_fee_min: i64,
+    pub dynamic_base_fee_max: i64,
+    pub dynamic_htlc_min: bool,
+    pub dynamic_htlc_min_normal: i64,
+    pub dynamic_htlc_min_raised: i64,
+    pub dynamic_htlc_min_depleted: f32,
+    pub dynamic_htlc_min_dust: i64,
+    pub dynamic_htlc_min_dust_limit: i64,
+    pub dynamic_htlc_max_ladder: Vec<u64>,
+    pub dynamic_htlc_max_ratio: f64,
+    pub dynamic_htlc_max_rounding: htlc_max::Rounding,
+    pub dynamic_htlc_max_source: htlc_max::Source,
+    pub dynamic_htlc_max_reserve: f64,
+    pub dynamic_fee_privacy: bool,
+    pub dynamic_fee_privacy_jitter: f64,
+    pub dynamic_fee_privacy_htlc_max_step: i64,
+    pub dynamic_fee_privacy_delay: i64,
+    pub dynamic_fee_competitors: bool,
+    pub dynamic_fee_competitor_percentile: f64,
+    pub dynamic_fee_competitor_min_count: i64,
+    pub dynamic_fee_report_windows: Vec<u64>,
+    pub dynamic_fee_refill_floor_window: i64,
+    pub dynamic_rebalance: bool,
+    pub dynamic_rebalance_interval: i64,
+    pub dynamic_rebalance_amount: i64,
+    pub dynamic_rebalance_budget: f64,
+    pub dynamic_rebalance_max_pairs: i64,
 }
 
 impl Config {
     pub fn default() -> Config {
         Config {
             dynamic_fees: false,
+            dynamic_fees_dry_run: false,
             dynamic_fee_min: 0,
             dynamic_fee_max: 1000,
             dynamic_fee_threshold: 0.2,
             dynamic_fee_width: 200,
             dynamic_fee_update_interval: 7200,
+            dynamic_fee_curve: FeeCurve::Linear,
+            dynamic_fee_target_ratio: 0.5,
+            dynamic_fee_target_learn_window: 0,
+            dynamic_fee_classify_window: 604800,
+            dynamic_fee_class_curves: BTreeMap::new(),
+            dynamic_fee_mode: pid::FeeMode::Curve,
+            dynamic_fee_pid_kp: 5,
+            dynamic_fee_pid_ki: 2,
+            dynamic_fee_pid_kd: 0,
+            dynamic_fee_forward_debounce: 60,
+            dynamic_fee_deadband: 0,
+            dynamic_fee_htlc_max_deadband: 0.0,
+            dynamic_fee_hysteresis: 0.0,
+            dynamic_base_fee: false,
+            dynamic_base_fee_min: 0,
+            dynamic_base_fee_max: 1000,
+            dynamic_htlc_min: false,
+            dynamic_htlc_min_normal: 0,
+            dynamic_htlc_min_raised: 1_000_000,
+            dynamic_htlc_min_depleted: 0.05,
+            dynamic_htlc_min_dust: 100_000,
+            dynamic_htlc_min_dust_limit: 100,
+            dynamic_htlc_max_ladder: htlc_max::DEFAULT_LADDER.to_vec(),
+            dynamic_htlc_max_ratio: 0.9,
+            dynamic_htlc_max_rounding: htlc_max::Rounding::Down,
+            dynamic_htlc_max_source: htlc_max::Source::Total,
+            dynamic_htlc_max_reserve: 0.01,
+            dynamic_fee_privacy: false,
+            dynamic_fee_privacy_jitter: 0.1,
+            dynamic_fee_privacy_htlc_max_step: 3,
+            dynamic_fee_privacy_delay: 600,
+            dynamic_fee_competitors: false,
+            dynamic_fee_competitor_percentile: 0.75,
+            dynamic_fee_competitor_min_count: 3,
+            dynamic_fee_report_windows: vec![86400, 604800, 2592000],
+            dynamic_fee_refill_floor_window: 0,
+            dynamic_rebalance: false,
+            dynamic_rebalance_interval: 21600,
+            dynamic_rebalance_amount: 100_000_000,
+            dynamic_rebalance_budget: 0.5,
+            dynamic_rebalance_max_pairs: 3,
         }
     }
 
     pub fn current() -> Arc<Config> {
-        CURRENT_CONFIG.with(|c| c.read().unwrap().clone())
+        CURRENT_CONFIG.read().unwrap().clone().unwrap_or_default()
     }
     pub fn make_current(self) {
-        CURRENT_CONFIG.with(|c| *c.write().unwrap() = Arc::new(self))
+        *CURRENT_CONFIG.write().unwrap() = Some(Arc::new(self))
     }
 }
 
-thread_local! {
-    static CURRENT_CONFIG: RwLock<Arc<Config>> = RwLock::new(Default::default());
+/// What we ask lightningd to advertise for a channel.  A `None` base fee or
+/// htlc_min leaves whatever is currently set alone.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct ChannelPolicy {
+    pub fee_ppm: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_fee_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub htlc_min_msat: Option<u64>,
+    pub htlc_max_msat: u64,
 }
 
-pub async fn set_channel_fees(config: Arc<Config>) -> Result<(), Error> {
+/// The outcome of evaluating one channel: what went in, what we'd advertise
+/// and, if nothing would be sent, why not.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChannelPlan {
+    pub short_channel_id: String,
+    pub peer_id: String,
+    pub inputs: PlanInputs,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<ChannelPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proposed: Option<ChannelPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip: Option<String>,
+    /// Controller state to keep, in PID mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<pid::PidState>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PlanInputs {
+    pub our_amount_msat: u64,
+    pub amount_msat: u64,
+    pub remote_proportion: f64,
+    pub connected: bool,
+    pub fee_min: i64,
+    pub fee_max: i64,
+    pub curve: String,
+    /// Local share the curve is centred on.
+    pub target_ratio: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_class: Option<classify::PeerClass>,
+    pub dust_count: usize,
+    /// Most we'd charge given what other channels to the peer charge.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub competitor_fee_cap: Option<u32>,
+    /// Least we'd charge, having paid this much to refill the channel.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refill_floor: Option<u32>,
+    /// The refill cost more than `fee_max` lets us charge, so the fee stops
+    /// at the max and the channel sells at a loss.
+    pub refill_floor_above_max: bool,
+    #[serde(rename = "override", skip_serializing_if = "Option::is_none")]
+    pub channel_override: Option<overrides::Override>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_applied: Option<store::AppliedPolicy>,
+}
+
+/// Shared by the scheduler and every handler, whichever thread they run on,
+/// so `ceebalancer-setconfig` takes effect everywhere at once.
+static CURRENT_CONFIG: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+
+/// Seconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub async fn set_channel_fees<B: LightningBackend>(
+    config: Arc<Config>,
+    state: &PluginState<B>,
+) -> Result<Vec<ChannelPlan>, Error> {
     log::debug!("Setting channel fees config: {:?}", config);
-    let channels = list_channels().await.unwrap();
+    let started = now();
+    let timer = Instant::now();
+    let channels = state.backend.list_channels().await?;
+    let plans = configure_channels(channels, &config, state).await;
+    state.status.record_run(started, timer.elapsed());
+    Ok(plans)
+}
+
+/// Re-evaluates only the given channels, e.g. the ones a forward just moved
+/// liquidity through.
+pub async fn set_channel_fees_for<B: LightningBackend>(
+    config: Arc<Config>,
+    state: &PluginState<B>,
+    short_channel_ids: &[String],
+) -> Result<Vec<ChannelPlan>, Error> {
+    log::debug!("Setting channel fees for {:?}", short_channel_ids);
+    let channels = state
+        .backend
+        .list_channels()
+        .await?
+        .into_iter()
+        .filter(|c| match &c.short_channel_id {
+            Some(id) => short_channel_ids.contains(id),
+            None => false,
+        })
+        .collect();
+    Ok(configure_channels(channels, &config, state).await)
+}
+
+/// Runs the full computation without touching any channel, and fills in what
+/// each channel currently advertises.
+pub async fn preview_channel_fees<B: LightningBackend>(
+    config: Arc<Config>,
+    state: &PluginState<B>,
+) -> Result<Vec<ChannelPlan>, Error> {
+    let channels = state.backend.list_channels().await?;
+    let inputs = RunInputs::load(&channels, &config, state).await;
+    let mut plans = vec![];
+    for channel in channels {
+        plans.push(plan_channel(&channel, &config, state, &inputs).await?);
+    }
+    Ok(plans)
+}
+
+/// What a run looks up once and shares between all of its channels.
+struct RunInputs {
+    refill_floors: HashMap<String, u32>,
+    learned_targets: HashMap<String, f64>,
+    peer_classes: HashMap<String, classify::PeerClass>,
+    competition: HashMap<String, competitors::Competition>,
+    /// What each channel advertises right now, if the node could tell us.
+    advertised: Option<HashMap<String, ChannelPolicy>>,
+}
+
+impl RunInputs {
+    async fn load<B: LightningBackend>(
+        channels: &[wire::Channel],
+        config: &Config,
+        state: &PluginState<B>,
+    ) -> RunInputs {
+        RunInputs {
+            refill_floors: refills::refill_floors(config, state).await,
+            learned_targets: targets::learned_targets(config, state).await,
+            peer_classes: classify::peer_classes(config, state).await,
+            competition: competition(channels, config, state).await,
+            advertised: advertised(state).await,
+        }
+    }
+}
+
+/// What each of our channels currently advertises.  Without it the deadband
+/// goes by what we last applied instead.
+async fn advertised<B: LightningBackend>(
+    state: &PluginState<B>,
+) -> Option<HashMap<String, ChannelPolicy>> {
+    let lookup = async {
+        let node_id = state.backend.get_node_id().await?;
+        state.backend.list_channel_policies(&node_id).await
+    };
+    match lookup.await {
+        Ok(policies) => Some(policies),
+        Err(e) => {
+            log::warn!("Error looking up our advertised policies: {:?}", e);
+            None
+        }
+    }
+}
+
+async fn configure_channels<B: LightningBackend>(
+    channels: Vec<wire::Channel>,
+    config: &Config,
+    state: &PluginState<B>,
+) -> Vec<ChannelPlan> {
+    let inputs = RunInputs::load(&channels, config, state).await;
+    let mut plans = vec![];
+    let mut delayed = vec![];
     for channel in channels {
         log::debug!("Channel under consideration: {:?}", channel);
-        match configure_channel(&channel, &config).await {
-            Ok(_) => log::debug!("Channel successfuly configured"),
-            Err(e) => log::error!("Error configuring channel: {:?}", e),
+        if let Err(e) = state.store.record_snapshot(&channel) {
+            log::warn!("Error recording channel snapshot: {:?}", e);
+        }
+        let plan = match plan_channel(&channel, config, state, &inputs).await {
+            Ok(plan) => plan,
+            Err(e) => {
+                log::error!("Error configuring channel: {:?}", e);
+                if let Some(short_channel_id) = &channel.short_channel_id {
+                    state.status.record_failure(short_channel_id, &e);
+                }
+                continue;
+            }
         };
+        plans.push(plan.clone());
+        if let Some(reason) = &plan.skip {
+            log::info!(
+                "Skipping update as {} (ID: {:?})",
+                reason,
+                &plan.short_channel_id
+            );
+            continue;
+        }
+        if config.dynamic_fees_dry_run {
+            log::info!(
+                "Dry run, not setting channel (ID: {:?} Policy: {:?})",
+                &plan.short_channel_id,
+                &plan.proposed
+            );
+            continue;
+        }
+        if config.dynamic_fee_privacy && config.dynamic_fee_privacy_delay > 0 {
+            // Spread the updates out so they can't be correlated by timing
+            delayed.push((privacy::random_delay(config), plan.short_channel_id));
+            continue;
+        }
+        report(&plan, state, apply_plan(&plan, state).await);
+    }
+    if !delayed.is_empty() {
+        // Left running on its own, so neither the caller nor whatever lock
+        // it holds waits out the delays
+        let config = config.clone();
+        let state = state.clone();
+        tokio::spawn(async move { apply_delayed(delayed, &config, &state, inputs).await });
     }
+    plans
+}
+
+/// Sends the updates privacy mode held back, each once its delay is up, in
+/// the background after the run has returned.  The balance may have moved in
+/// the meantime, so the channels are read again and re-planned rather than
+/// sending what was worked out at the start of the run.
+async fn apply_delayed<B: LightningBackend>(
+    mut delayed: Vec<(Duration, String)>,
+    config: &Config,
+    state: &PluginState<B>,
+    mut inputs: RunInputs,
+) {
+    delayed.sort();
+    let started = tokio::time::Instant::now();
+    let mut delayed = delayed.into_iter().peekable();
+    while let Some(&(delay, _)) = delayed.peek() {
+        tokio::time::sleep_until(started + delay).await;
+        let channels = match state.backend.list_channels().await {
+            Ok(channels) => channels,
+            Err(e) => {
+                log::error!("Error re-reading channels for delayed updates: {:?}", e);
+                return;
+            }
+        };
+        inputs.advertised = advertised(state).await;
+        // Everything that came due while we were waiting on the backend
+        let due = started.elapsed();
+        while let Some((_, short_channel_id)) = delayed.next_if(|(delay, _)| *delay <= due) {
+            let channel = match channels
+                .iter()
+                .find(|c| c.short_channel_id.as_ref() == Some(&short_channel_id))
+            {
+                Some(channel) => channel,
+                None => {
+                    log::info!(
+                        "Channel went away before its delayed update (ID: {:?})",
+                        short_channel_id
+                    );
+                    continue;
+                }
+            };
+            match plan_channel(channel, config, state, &inputs).await {
+                Ok(plan) => match &plan.skip {
+                    Some(reason) => log::info!(
+                        "Skipping delayed update as {} (ID: {:?})",
+                        reason,
+                        &plan.short_channel_id
+                    ),
+                    None => report(&plan, state, apply_plan(&plan, state).await),
+                },
+                Err(e) => {
+                    log::error!("Error configuring channel: {:?}", e);
+                    state.status.record_failure(&short_channel_id, &e);
+                }
+            }
+        }
+    }
+}
+
+fn report<B>(plan: &ChannelPlan, state: &PluginState<B>, result: Result<(), Error>) {
+    match result {
+        Ok(_) => {
+            log::debug!("Channel successfuly configured");
+            state.status.record_success(&plan.short_channel_id);
+        }
+        Err(e) => {
+            log::error!("Error configuring channel: {:?}", e);
+            state.status.record_failure(&plan.short_channel_id, &e);
+        }
+    };
+}
+
+async fn apply_plan<B: LightningBackend>(
+    plan: &ChannelPlan,
+    state: &PluginState<B>,
+) -> Result<(), Error> {
+    let policy = match &plan.proposed {
+        Some(policy) => policy,
+        None => return Ok(()),
+    };
+    state
+        .backend
+        .set_channel_policy(&plan.short_channel_id, policy)
+        .await
+        .map_err(|e| {
+            log::error!("Error setting a channel through client: {:?}", e);
+            e
+        })?;
+    state.store.record_applied(&plan.short_channel_id, policy)?;
+    // The controller only moves on with updates that went out, so skipped
+    // and dry-run ones don't wind up the integral without the fee moving
+    if let Some(pid) = &plan.pid {
+        state.store.record_pid(&plan.short_channel_id, pid)?;
+    }
+    log::info!(
+        "Channel set (ID: {:?} Fee: {}, Base: {:?}, Min HTLC: {:?}, Max HTLC: {})",
+        &plan.short_channel_id,
+        policy.fee_ppm,
+        policy.base_fee_msat,
+        policy.htlc_min_msat,
+        policy.htlc_max_msat,
+    );
     Ok(())
 }
 
-async fn configure_channel(channel: &wire::Channel, config: &Config) -> Result<(), Error> {
-    if channel.connected {
-        let short_channel_id = &channel.short_channel_id.clone().unwrap();
-        let fee_target = calculate_fee_target(&channel, &config).await.unwrap();
-        let htlc_max_msat_target = calculate_htlc_max(&channel, &config).await.unwrap();
-        log::debug!(
-            "Calculated target rate for channel (ChannelID: {:?}, Target: {:?})",
-            &short_channel_id,
-            &fee_target
-        );
-        set_channel_fee(short_channel_id, fee_target, htlc_max_msat_target)
-            .await
-            .map_err(|e| {
-                log::error!("Error setting a channel through client: {:?}", e);
-                e
-            })?;
-        log::info!(
-            "Channel set (ID: {:?} Fee: {}, Max HTLC: {})",
-            &short_channel_id,
+/// Works out the policy for a channel and whether it should be sent, without
+/// sending anything.
+async fn plan_channel<B: LightningBackend>(
+    channel: &wire::Channel,
+    config: &Config,
+    state: &PluginState<B>,
+    inputs: &RunInputs,
+) -> Result<ChannelPlan, Error> {
+    let short_channel_id = channel.short_channel_id.clone().unwrap_or_default();
+    let channel_override = state.store.override_for(channel);
+    let history = state.store.channel(&short_channel_id);
+    let last_applied = history.as_ref().and_then(|h| h.last_applied.clone());
+    let previous_pid = history.and_then(|h| h.pid);
+    let mut plan = ChannelPlan {
+        short_channel_id: short_channel_id.clone(),
+        peer_id: channel.peer_id.clone(),
+        inputs: PlanInputs {
+            our_amount_msat: channel.our_amount_msat.msat(),
+            amount_msat: channel.amount_msat.msat(),
+            remote_proportion: remote_proportion(channel),
+            connected: channel.connected,
+            fee_min: config.dynamic_fee_min,
+            fee_max: config.dynamic_fee_max,
+            curve: config.dynamic_fee_curve.to_string(),
+            target_ratio: config.dynamic_fee_target_ratio,
+            peer_class: inputs.peer_classes.get(&channel.peer_id).copied(),
+            dust_count: 0,
+            competitor_fee_cap: None,
+            refill_floor: None,
+            refill_floor_above_max: false,
+            channel_override: channel_override.clone(),
+            last_applied: last_applied.clone(),
+        },
+        current: inputs
+            .advertised
+            .as_ref()
+            .and_then(|advertised| advertised.get(&short_channel_id).cloned()),
+        proposed: None,
+        skip: None,
+        pid: None,
+    };
+    if !channel.connected {
+        plan.skip = Some("channel is not currently online".to_string());
+        return Ok(plan);
+    }
+    if channel.short_channel_id.is_none() {
+        plan.skip = Some("channel has no short_channel_id yet".to_string());
+        return Ok(plan);
+    }
+    let channel_override = channel_override.unwrap_or_default();
+    if channel_override.exclude {
+        plan.skip = Some("channel is excluded from management".to_string());
+        return Ok(plan);
+    }
+    // What we've learned about the channel wins over the configured target
+    // and curve, an override over both
+    let mut learned = config.clone();
+    if let Some(target) = inputs.learned_targets.get(&short_channel_id) {
+        learned.dynamic_fee_target_ratio = *target;
+    }
+    if let Some(curve) = plan
+        .inputs
+        .peer_class
+        .and_then(|class| config.dynamic_fee_class_curves.get(&class))
+    {
+        learned.dynamic_fee_curve = curve.clone();
+    }
+    let config = &channel_override.apply(&learned);
+    plan.inputs.fee_min = config.dynamic_fee_min;
+    plan.inputs.fee_max = config.dynamic_fee_max;
+    plan.inputs.curve = config.dynamic_fee_curve.to_string();
+    plan.inputs.target_ratio = config.dynamic_fee_target_ratio;
+    // The global range can change under an override after it was set
+    if config.dynamic_fee_min > config.dynamic_fee_max {
+        plan.skip = Some(format!(
+            "override leaves no fee range ({} to {})",
+            config.dynamic_fee_min, config.dynamic_fee_max
+        ));
+        return Ok(plan);
+    }
+
+    let last_fee = last_applied.as_ref().map(|p| p.policy.fee_ppm);
+    let fee_target = match channel_override.fixed_fee {
+        Some(fee) => fee,
+        None => {
+            let mut fee_target = match config.dynamic_fee_mode {
+                pid::FeeMode::Curve => calculate_fee_target(&channel, &config).await?,
+                pid::FeeMode::Pid => {
+                    let start_fee = match last_fee {
+                        Some(fee) => fee,
+                        None => calculate_fee_target(&channel, &config).await?,
+                    };
+                    let (fee, pid) =
+                        pid::pid_fee(channel, config, previous_pid.as_ref(), start_fee, now());
+                    plan.pid = Some(pid);
+                    fee
+                }
+            };
+            if let Some(competition) = inputs.competition.get(&channel.peer_id) {
+                plan.inputs.competitor_fee_cap = competitors::fee_cap(competition, config);
+            }
+            if let Some(cap) = plan.inputs.competitor_fee_cap {
+                fee_target = fee_target.min(cap);
+            }
+            // The controller already moves in small steps
+            let fee_target = match config.dynamic_fee_mode {
+                pid::FeeMode::Curve => apply_hysteresis(channel, config, last_fee, fee_target),
+                pid::FeeMode::Pid => fee_target,
+            };
+            // A fee held by hysteresis is already jittered
+            let fee_target = if config.dynamic_fee_privacy && Some(fee_target) != last_fee {
+                privacy::jitter_fee(
+                    state.store.privacy_seed(),
+                    &short_channel_id,
+                    fee_target,
+                    config,
+                )
+            } else {
+                fee_target
+            };
+            // Selling the liquidity for less than it cost to get loses money,
+            // whatever the curve or the competition say.  The max still wins,
+            // as it's the operator's limit
+            plan.inputs.refill_floor = inputs.refill_floors.get(&short_channel_id).copied();
+            let fee_max = config.dynamic_fee_max.max(0) as u32;
+            match plan.inputs.refill_floor {
+                Some(floor) if floor > fee_max => {
+                    plan.inputs.refill_floor_above_max = true;
+                    fee_target.max(fee_max)
+                }
+                Some(floor) => fee_target.max(floor),
+                None => fee_target,
+            }
+        }
+    };
+    if let Some(pid) = &plan.pid {
+        plan.pid = Some(pid::back_calculate(
+            pid,
+            previous_pid.as_ref(),
             fee_target,
-            htlc_max_msat_target,
-        );
-    } else {
-        log::info!("Skipping update as channel is not currently online");
+            config,
+        ));
     }
-    Ok(())
+    let base_fee_target = match channel_override.fixed_base_fee {
+        Some(base_fee) => Some(base_fee),
+        None => calculate_base_fee(&channel, &config),
+    };
+    let htlc_max_msat_target = calculate_htlc_max(&channel, &config).await?;
+    let since = now().saturating_sub(config.dynamic_fee_update_interval.max(0) as u64);
+    let dust_count = state.dust.count(&short_channel_id, since);
+    plan.inputs.dust_count = dust_count;
+    let htlc_min_msat_target =
+        calculate_htlc_min(&channel, &config, dust_count, htlc_max_msat_target);
+    log::debug!(
+        "Calculated target rate for channel (ChannelID: {:?}, Target: {:?}, Base: {:?})",
+        &short_channel_id,
+        &fee_target,
+        &base_fee_target
+    );
+    let policy = ChannelPolicy {
+        fee_ppm: fee_target,
+        base_fee_msat: base_fee_target,
+        htlc_min_msat: htlc_min_msat_target,
+        htlc_max_msat: htlc_max_msat_target,
+    };
+    // Measured from what the node advertises, so a fee changed behind our
+    // back gets put right, and losing the store doesn't mean sending the
+    // same policy every run
+    let advertised = plan
+        .current
+        .clone()
+        .or_else(|| last_applied.map(|last| last.policy));
+    if let Some(advertised) = &advertised {
+        if within_deadband(advertised, &policy, config) {
+            plan.skip = Some("channel is within deadband".to_string());
+        }
+    }
+    plan.proposed = Some(policy);
+    Ok(plan)
 }
 
-async fn calculate_htlc_max(channel: &wire::Channel, config: &Config) -> Result<u64, Error> {
-    let ours: u64 = channel.our_amount_msat.msat() as u64;
-    let values = [
-        1_000,
-        100_000,
-        250_000,
-        1_000_000,
-        10_000_000,
-        50_000_000,
-        100_000_000,
-        250_000_000,
-        500_000_000,
-        1_000_000_000,
-        2_000_000_000,
-        3_000_000_000,
-        4_000_000_000,
-        5_000_000_000,
-        7_500_000_000,
-        10_000_000_000,
-        15_000_000_000,
-        20_000_000_000,
-    ];
-    let target = values.iter().rev().find(|&x| &ours >= x);
-    let t = match target {
-        Some(t) => t,
-        None => &ours,
+/// What competes with each of the channels' peers in the gossip graph, looked
+/// up once per peer.  For a peer with too few other channels to go on, what
+/// it costs to reach the peers near it counts as well.  Not knowing the
+/// competition isn't a reason to leave a channel alone, so failures just
+/// mean no cap.
+async fn competition<B: LightningBackend>(
+    channels: &[wire::Channel],
+    config: &Config,
+    state: &PluginState<B>,
+) -> HashMap<String, competitors::Competition> {
+    let mut competition = HashMap::new();
+    if !config.dynamic_fee_competitors {
+        return competition;
+    }
+    let node_id = match state.backend.get_node_id().await {
+        Ok(node_id) => node_id,
+        Err(e) => {
+            log::warn!("Error looking up competing channels: {:?}", e);
+            return competition;
+        }
     };
-    let capped = (0.9 * (*t as f64)).round();
-    Ok(capped as u64)
+    for channel in channels {
+        let peer_id = &channel.peer_id;
+        if competition.contains_key(peer_id) {
+            continue;
+        }
+        let lookup = async {
+            let graph = state.backend.list_channels_to(peer_id).await?;
+            let mut peer = competitors::Competition::new(&graph, &node_id, peer_id);
+            if (peer.len() as i64) < config.dynamic_fee_competitor_min_count {
+                for neighbour in competitors::Competition::neighbours(&graph, &node_id, peer_id) {
+                    let near = state.backend.list_channels_to(&neighbour).await?;
+                    peer.add(&near, &neighbour, &[&node_id, peer_id]);
+                }
+            }
+            Ok::<_, Error>(peer)
+        };
+        match lookup.await {
+            Ok(peer) => {
+                log::debug!("{} competing channels to {}", peer.len(), peer_id);
+                competition.insert(peer_id.clone(), peer);
+            }
+            Err(e) => log::warn!("Error looking up competing channels: {:?}", e),
+        }
+    }
+    competition
 }
 
-async fn calculate_fee_target(channel: &wire::Channel, config: &Config) -> Result<u32, Error> {
+pub(crate) async fn calculate_htlc_max(
+    channel: &wire::Channel,
+    config: &Config,
+) -> Result<u64, Error> {
+    let ours: u64 = match config.dynamic_htlc_max_source {
+        htlc_max::Source::Total => channel.our_amount_msat.msat(),
+        htlc_max::Source::Spendable => match channel.spendable_msat {
+            Some(spendable) => spendable.msat(),
+            // The backend couldn't tell us, so estimate the reserve
+            None => {
+                let reserve =
+                    (channel.amount_msat.msat() as f64 * config.dynamic_htlc_max_reserve) as u64;
+                channel.our_amount_msat.msat().saturating_sub(reserve)
+            }
+        },
+    };
+    let t = if config.dynamic_fee_privacy {
+        let ladder = privacy::coarsen_ladder(
+            &config.dynamic_htlc_max_ladder,
+            config.dynamic_fee_privacy_htlc_max_step,
+        );
+        match config.dynamic_htlc_max_rounding {
+            htlc_max::Rounding::None => htlc_max::Rounding::Down.apply(ours, &ladder),
+            rounding => rounding.apply(ours, &ladder),
+        }
+    } else {
+        config
+            .dynamic_htlc_max_rounding
+            .apply(ours, &config.dynamic_htlc_max_ladder)
+    };
+    let capped = (config.dynamic_htlc_max_ratio * (t as f64)).round();
+    // Nearest rounding can land above what we actually have
+    Ok((capped as u64).min(ours))
+}
+
+pub(crate) async fn calculate_fee_target(
+    channel: &wire::Channel,
+    config: &Config,
+) -> Result<u32, Error> {
     let ours: f64 = channel.our_amount_msat.msat() as f64;
     let total: f64 = channel.amount_msat.msat() as f64;
-    let proportion = 1.0 - (ours / total);
+    let proportion = remote_proportion(channel);
 
-    let min_threshold_ratio: f64 = config.dynamic_fee_threshold as f64;
-    let max_threshold_ratio: f64 = 1.0 - config.dynamic_fee_threshold as f64;
+    log::debug!(
+        "Target calculation (Ours: {}, Total: {}, Proportion: {}, Curve: {}",
+        ours,
+        total,
+        proportion,
+        config.dynamic_fee_curve
+    );
+    Ok(fee_for_proportion(proportion, config))
+}
+
+pub(crate) fn remote_proportion(channel: &wire::Channel) -> f64 {
+    let ours: f64 = channel.our_amount_msat.msat() as f64;
+    let total: f64 = channel.amount_msat.msat() as f64;
+    1.0 - (ours / total)
+}
 
+fn fee_for_proportion(proportion: f64, config: &Config) -> u32 {
     let max: f64 = config.dynamic_fee_max as f64;
     let min: f64 = config.dynamic_fee_min as f64;
 
@@ -131,24 +769,117 @@ async fn calculate_fee_target(channel: &wire::Channel, config: &Config) -> Resul
 
     let range = max - min;
     log::debug!("Min {} Max {} Range {}", min, max, range);
-    log::debug!(
-        "Target calculation (Ours: {}, Total: {}, Proportion: {}, Range: {}",
-        ours,
-        total,
-        proportion,
-        range
-    );
-    let target = if proportion <= min_threshold_ratio {
-        min
-    } else if proportion >= max_threshold_ratio {
-        max
+    let target = config
+        .dynamic_fee_curve
+        .fee(fee_strategy::recentre(proportion, config), config)
+        .max(min)
+        .min(max);
+
+    ((target / width).floor() * width) as u32
+}
+
+/// Keeps the previously applied fee while the balance is within
+/// `dynamic_fee_hysteresis` of the point where it was chosen, so a channel
+/// sitting on a step boundary doesn't flip between two fees every run.
+pub(crate) fn apply_hysteresis(
+    channel: &wire::Channel,
+    config: &Config,
+    last_fee: Option<u32>,
+    fee_target: u32,
+) -> u32 {
+    let band = config.dynamic_fee_hysteresis as f64;
+    let last_fee = match last_fee {
+        Some(f) if band > 0.0 => f,
+        _ => return fee_target,
+    };
+    let proportion = remote_proportion(channel);
+    let low = fee_for_proportion((proportion - band).max(0.0), config);
+    let high = fee_for_proportion((proportion + band).min(1.0), config);
+    if last_fee >= low.min(high) && last_fee <= low.max(high) {
+        last_fee
+    } else {
+        fee_target
+    }
+}
+
+/// Base fee ramps from `dynamic_base_fee_min` to `dynamic_base_fee_max` over
+/// the same balance window as the proportional fee, so e.g. min 0 gives a
+/// zero base fee while we're local-heavy.
+pub(crate) fn calculate_base_fee(channel: &wire::Channel, config: &Config) -> Option<u64> {
+    if !config.dynamic_base_fee {
+        return None;
+    }
+    let proportion = fee_strategy::recentre(remote_proportion(channel), config);
+    let position = fee_strategy::balance_position(proportion, config);
+    let min = config.dynamic_base_fee_min as f64;
+    let max = config.dynamic_base_fee_max as f64;
+    Some((min + position * (max - min)).round() as u64)
+}
+
+/// Raises htlc_min while the channel is nearly depleted or has been flooded
+/// with dust-sized HTLCs, and drops it back once neither is true.  Never
+/// exceeds htlc_max.
+fn calculate_htlc_min(
+    channel: &wire::Channel,
+    config: &Config,
+    dust_count: usize,
+    htlc_max_msat: u64,
+) -> Option<u64> {
+    if !config.dynamic_htlc_min {
+        return None;
+    }
+    let local_proportion = 1.0 - remote_proportion(channel);
+    let depleted = local_proportion < config.dynamic_htlc_min_depleted as f64;
+    let flooded = dust_count as i64 >= config.dynamic_htlc_min_dust_limit;
+    let target = if depleted || flooded {
+        log::debug!(
+            "Raising htlc_min (Depleted: {}, Dust HTLCs: {})",
+            depleted,
+            dust_count
+        );
+        config.dynamic_htlc_min_raised
     } else {
-        let nom = proportion - min_threshold_ratio;
-        let denom = max_threshold_ratio - min_threshold_ratio;
-        ((nom / denom) * range) + min
+        config.dynamic_htlc_min_normal
     };
+    Some((target.max(0) as u64).min(htlc_max_msat))
+}
 
-    Ok(((target / width).floor() * width) as u32)
+/// Whether the new policy is close enough to the last applied one that
+/// sending a `channel_update` isn't worth the gossip.
+pub(crate) fn within_deadband(
+    last: &ChannelPolicy,
+    policy: &ChannelPolicy,
+    config: &Config,
+) -> bool {
+    let fee_delta = (policy.fee_ppm as i64 - last.fee_ppm as i64).abs();
+    let htlc_max_delta = (policy.htlc_max_msat as f64 - last.htlc_max_msat as f64).abs();
+    let htlc_max_allowed = last.htlc_max_msat as f64 * config.dynamic_fee_htlc_max_deadband as f64;
+    let base_fee_unchanged =
+        policy.base_fee_msat.is_none() || policy.base_fee_msat == last.base_fee_msat;
+    let htlc_min_unchanged =
+        policy.htlc_min_msat.is_none() || policy.htlc_min_msat == last.htlc_min_msat;
+    fee_delta <= config.dynamic_fee_deadband
+        && htlc_max_delta <= htlc_max_allowed
+        && base_fee_unchanged
+        && htlc_min_unchanged
+}
+
+/// A connected, normal channel for tests, with `ours` of `channel_size` msat
+/// on our side.
+#[cfg(test)]
+pub(crate) fn test_channel(channel_size: u64, ours: u64) -> wire::Channel {
+    wire::Channel {
+        amount_msat: primitives::Amount { msat: channel_size },
+        our_amount_msat: primitives::Amount { msat: ours },
+        connected: true,
+        peer_id: "039b9e260863e6d8735325b286931d73be9f8e766970ad4fe1cbcc470cd8964635".to_string(),
+        state: wire::ChannelState::CHANNELD_NORMAL,
+        funding_txid: "724ee70bc1670368c3db3c2ebed30d00fa595774356cebf509196c68a471ca91"
+            .to_string(),
+        funding_output: 0,
+        short_channel_id: Some("123x123x0".to_string()),
+        spendable_msat: None,
+    }
 }
 
 #[cfg(test)]
@@ -165,6 +896,7 @@ mod test {
             dynamic_fee_threshold: 0.2,
             dynamic_fee_min: 100,
             dynamic_fee_max: 500,
+            ..Config::default()
         };
 
         let test_cases = vec![
@@ -186,6 +918,7 @@ mod test {
                     .to_string(),
                 funding_output: 0,
                 short_channel_id: Some("123x123x0".to_string()),
+                spendable_msat: None,
             };
 
             let calc = calculate_htlc_max(&c, &config).await.unwrap();
@@ -193,6 +926,62 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn calculate_htlc_max_from_spendable() {
+        let config = Config {
+            dynamic_htlc_max_ratio: 1.0,
+            dynamic_htlc_max_rounding: htlc_max::Rounding::None,
+            dynamic_htlc_max_source: htlc_max::Source::Spendable,
+            dynamic_htlc_max_reserve: 0.01,
+            ..Config::default()
+        };
+
+        let c = test_channel(50_000_000_000, 40_000_000_000);
+        assert_eq!(
+            calculate_htlc_max(&c, &config).await.unwrap(),
+            39_500_000_000
+        );
+
+        let c = test_channel(50_000_000_000, 100_000_000);
+        assert_eq!(calculate_htlc_max(&c, &config).await.unwrap(), 0);
+
+        // The spendable amount the backend reports wins over the estimate
+        let c = wire::Channel {
+            spendable_msat: Some(primitives::Amount::from_msat(38_000_000_000)),
+            ..test_channel(50_000_000_000, 40_000_000_000)
+        };
+        assert_eq!(
+            calculate_htlc_max(&c, &config).await.unwrap(),
+            38_000_000_000
+        );
+
+        let config = Config {
+            dynamic_htlc_max_ratio: 0.5,
+            dynamic_htlc_max_ladder: vec![10_000_000_000, 40_000_000_000],
+            ..Config::default()
+        };
+        let c = test_channel(50_000_000_000, 45_000_000_000);
+        assert_eq!(
+            calculate_htlc_max(&c, &config).await.unwrap(),
+            20_000_000_000
+        );
+    }
+
+    #[tokio::test]
+    async fn privacy_mode_coarsens_htlc_max() {
+        let config = Config {
+            dynamic_htlc_max_ratio: 1.0,
+            dynamic_htlc_max_ladder: vec![1_000, 2_000, 3_000, 4_000, 5_000],
+            dynamic_htlc_max_rounding: htlc_max::Rounding::None,
+            dynamic_fee_privacy: true,
+            dynamic_fee_privacy_htlc_max_step: 2,
+            ..Config::default()
+        };
+
+        let c = test_channel(10_000, 4_500);
+        assert_eq!(calculate_htlc_max(&c, &config).await.unwrap(), 3_000);
+    }
+
     #[tokio::test]
     async fn calculate_imbalanced_channel() {
         let config = Config {
@@ -202,6 +991,7 @@ mod test {
             dynamic_fee_threshold: 0.2,
             dynamic_fee_min: 10,
             dynamic_fee_max: 500,
+            ..Config::default()
         };
 
         let test_cases = vec![
@@ -224,6 +1014,7 @@ mod test {
                     .to_string(),
                 funding_output: 0,
                 short_channel_id: Some("123x123x0".to_string()),
+                spendable_msat: None,
             };
 
             let target = calculate_fee_target(&c, &config).await.unwrap();
@@ -231,6 +1022,416 @@ mod test {
         }
     }
 
+    /// Serves a fixed set of channels and remembers the policies it was asked
+    /// to set.
+    #[derive(Clone, Default)]
+    struct FakeBackend {
+        channels: Vec<wire::Channel>,
+        graph: Vec<wire::ListChannel>,
+        sendpays: Vec<wire::SendPay>,
+        invoices: Vec<wire::ListInvoice>,
+        policies: HashMap<String, ChannelPolicy>,
+        set: Arc<std::sync::Mutex<Vec<(String, ChannelPolicy)>>>,
+    }
+
+    impl LightningBackend for FakeBackend {
+        async fn get_info(&self) -> Result<String, Error> {
+            Ok("{}".to_string())
+        }
+        async fn get_node_id(&self) -> Result<String, Error> {
+            Ok("02".to_string())
+        }
+        async fn list_channels(&self) -> Result<Vec<wire::Channel>, Error> {
+            Ok(self.channels.clone())
+        }
+        async fn list_channel_policies(
+            &self,
+            _node_id: &str,
+        ) -> Result<HashMap<String, ChannelPolicy>, Error> {
+            Ok(self.policies.clone())
+        }
+        async fn list_channels_to(&self, node_id: &str) -> Result<Vec<wire::ListChannel>, Error> {
+            Ok(self
+                .graph
+                .iter()
+                .filter(|c| c.destination == node_id)
+                .cloned()
+                .collect())
+        }
+        async fn set_channel_policy(
+            &self,
+            short_channel_id: &str,
+            policy: &ChannelPolicy,
+        ) -> Result<(), Error> {
+            self.set
+                .lock()
+                .unwrap()
+                .push((short_channel_id.to_string(), policy.clone()));
+            Ok(())
+        }
+        async fn onchain_balance(&self) -> Result<u64, Error> {
+            Ok(0)
+        }
+        async fn list_forwards(&self) -> Result<Vec<wire::Forward>, Error> {
+            Ok(vec![])
+        }
+        async fn list_sendpays(&self) -> Result<Vec<wire::SendPay>, Error> {
+            Ok(self.sendpays.clone())
+        }
+        async fn get_invoice(
+            &self,
+            payment_hash: &str,
+        ) -> Result<Option<wire::ListInvoice>, Error> {
+            Ok(self
+                .invoices
+                .iter()
+                .find(|i| i.payment_hash == payment_hash)
+                .cloned())
+        }
+        async fn get_channel(
+            &self,
+            short_channel_id: &str,
+        ) -> Result<Vec<wire::ListChannel>, Error> {
+            Ok(self
+                .graph
+                .iter()
+                .filter(|c| c.short_channel_id == short_channel_id)
+                .cloned()
+                .collect())
+        }
+        async fn get_route(
+            &self,
+            _from: &str,
+            _to: &str,
+            _amount_msat: u64,
+            _exclude: &[String],
+        ) -> Result<Vec<wire::RouteHop>, Error> {
+            Err(anyhow::anyhow!("no route"))
+        }
+        async fn create_invoice(
+            &self,
+            _amount_msat: u64,
+            _label: &str,
+            _description: &str,
+        ) -> Result<wire::Invoice, Error> {
+            Err(anyhow::anyhow!("no invoices"))
+        }
+        async fn delete_invoice(&self, _label: &str) -> Result<(), Error> {
+            Ok(())
+        }
+        async fn send_to_route(
+            &self,
+            _route: &[wire::RouteHop],
+            _invoice: &wire::Invoice,
+        ) -> Result<(), Error> {
+            Err(anyhow::anyhow!("no payments"))
+        }
+    }
+
+    #[tokio::test]
+    async fn set_channel_fees_goes_through_the_backend() {
+        let config = Arc::new(Config {
+            dynamic_fee_width: 10,
+            dynamic_fee_min: 10,
+            dynamic_fee_max: 500,
+            ..Config::default()
+        });
+        let mut offline = test_channel(1000, 0);
+        offline.connected = false;
+        offline.short_channel_id = Some("2x2x2".to_string());
+        let backend = FakeBackend {
+            channels: vec![test_channel(1000, 0), offline],
+            ..FakeBackend::default()
+        };
+        let state = PluginState::new(backend.clone());
+
+        set_channel_fees(config.clone(), &state).await.unwrap();
+        let set = backend.set.lock().unwrap().clone();
+        assert_eq!(set.len(), 1);
+        assert_eq!(set[0].0, "123x123x0");
+        assert_eq!(set[0].1.fee_ppm, 500);
+
+        let dry_run = Arc::new(Config {
+            dynamic_fees_dry_run: true,
+            ..(*config).clone()
+        });
+        set_channel_fees(dry_run, &state).await.unwrap();
+        assert_eq!(backend.set.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn competitors_cap_the_fee() {
+        let config = Arc::new(Config {
+            dynamic_fee_width: 10,
+            dynamic_fee_max: 500,
+            dynamic_fee_competitors: true,
+            dynamic_fee_competitor_percentile: 0.5,
+            dynamic_fee_competitor_min_count: 2,
+            ..Config::default()
+        });
+        let channel = test_channel(1000, 0);
+        let competitor = |source: &str, fee_per_millionth| wire::ListChannel {
+            source: source.to_string(),
+            destination: channel.peer_id.clone(),
+            short_channel_id: "9x9x9".to_string(),
+            amount_msat: primitives::Amount::from_sat(1_000_000),
+            base_fee_millisatoshi: 1000,
+            fee_per_millionth,
+            delay: 40,
+            htlc_minimum_msat: primitives::Amount::from_msat(0),
+            htlc_maximum_msat: None,
+            active: true,
+        };
+        let backend = FakeBackend {
+            channels: vec![channel.clone()],
+            graph: vec![
+                competitor("02", 2000),
+                competitor("03a", 150),
+                competitor("03b", 250),
+                competitor("03c", 900),
+            ],
+            ..FakeBackend::default()
+        };
+        let state = PluginState::new(backend);
+
+        let plans = preview_channel_fees(config.clone(), &state).await.unwrap();
+        assert_eq!(plans[0].inputs.competitor_fee_cap, Some(250));
+        assert_eq!(plans[0].proposed.as_ref().unwrap().fee_ppm, 250);
+    }
+
+    #[tokio::test]
+    async fn deadband_goes_by_what_is_advertised() {
+        let config = Arc::new(Config {
+            dynamic_fee_width: 10,
+            dynamic_fee_max: 500,
+            ..Config::default()
+        });
+        let channel = test_channel(1000, 500);
+        let backend = FakeBackend {
+            channels: vec![channel.clone()],
+            ..FakeBackend::default()
+        };
+        let plans = preview_channel_fees(config.clone(), &PluginState::new(backend))
+            .await
+            .unwrap();
+        let proposed = plans[0].proposed.clone().unwrap();
+
+        // Changed by hand since we last set it
+        let changed = ChannelPolicy {
+            fee_ppm: proposed.fee_ppm + 100,
+            ..proposed.clone()
+        };
+        let backend = FakeBackend {
+            channels: vec![channel.clone()],
+            policies: HashMap::from([("123x123x0".to_string(), changed)]),
+            ..FakeBackend::default()
+        };
+        let state = PluginState::new(backend);
+        state.store.record_applied("123x123x0", &proposed).unwrap();
+        let plans = preview_channel_fees(config.clone(), &state).await.unwrap();
+        assert_eq!(plans[0].skip, None);
+
+        // Already advertised, with nothing in the store
+        let backend = FakeBackend {
+            channels: vec![channel],
+            policies: HashMap::from([("123x123x0".to_string(), proposed)]),
+            ..FakeBackend::default()
+        };
+        let plans = preview_channel_fees(config, &PluginState::new(backend))
+            .await
+            .unwrap();
+        assert_eq!(plans[0].skip.as_deref(), Some("channel is within deadband"));
+    }
+
+    #[tokio::test]
+    async fn override_without_a_fee_range_is_skipped() {
+        let config = Arc::new(Config {
+            dynamic_fee_max: 500,
+            ..Config::default()
+        });
+        let backend = FakeBackend {
+            channels: vec![test_channel(1000, 500)],
+            ..FakeBackend::default()
+        };
+        let state = PluginState::new(backend);
+        let policy = overrides::Override {
+            min: Some(2000),
+            ..overrides::Override::default()
+        };
+        state.store.set_override("123x123x0", Some(policy)).unwrap();
+
+        let plans = preview_channel_fees(config, &state).await.unwrap();
+        assert_eq!(
+            plans[0].skip.as_deref(),
+            Some("override leaves no fee range (2000 to 500)")
+        );
+        assert_eq!(plans[0].proposed, None);
+    }
+
+    #[tokio::test]
+    async fn refill_floor_stops_at_fee_max() {
+        let config = Arc::new(Config {
+            dynamic_fee_width: 10,
+            dynamic_fee_max: 500,
+            dynamic_fee_refill_floor_window: 86400,
+            ..Config::default()
+        });
+        let backend = FakeBackend {
+            channels: vec![test_channel(1000, 1000)],
+            sendpays: vec![wire::SendPay {
+                payment_hash: "aa".to_string(),
+                status: "complete".to_string(),
+                destination: Some("02".to_string()),
+                amount_msat: Some(primitives::Amount::from_msat(1_000_000)),
+                amount_sent_msat: primitives::Amount::from_msat(1_001_000),
+                created_at: now(),
+            }],
+            invoices: vec![wire::ListInvoice {
+                label: "aa".to_string(),
+                payment_hash: "aa".to_string(),
+                status: "paid".to_string(),
+                description: Some("1x1x1 to 123x123x0".to_string()),
+            }],
+            ..FakeBackend::default()
+        };
+        let state = PluginState::new(backend);
+
+        let plans = preview_channel_fees(config.clone(), &state).await.unwrap();
+        assert_eq!(plans[0].inputs.refill_floor, Some(1000));
+        assert!(plans[0].inputs.refill_floor_above_max);
+        assert_eq!(plans[0].proposed.as_ref().unwrap().fee_ppm, 500);
+    }
+
+    #[tokio::test]
+    async fn hysteresis_holds_fee_near_step_boundary() {
+        let config = Config {
+            dynamic_fee_width: 100,
+            dynamic_fee_threshold: 0.2,
+            dynamic_fee_min: 0,
+            dynamic_fee_max: 600,
+            dynamic_fee_hysteresis: 0.02,
+            ..Config::default()
+        };
+
+        // The 300 step starts at 0.5 remote, so 0.49 remote is a 200 but
+        // within the band of the step above it
+        let c = test_channel(1000, 510);
+        let target = calculate_fee_target(&c, &config).await.unwrap();
+        assert_eq!(target, 200);
+        assert_eq!(apply_hysteresis(&c, &config, Some(300), target), 300);
+        assert_eq!(apply_hysteresis(&c, &config, None, target), 200);
+
+        let c = test_channel(1000, 450);
+        let target = calculate_fee_target(&c, &config).await.unwrap();
+        assert_eq!(apply_hysteresis(&c, &config, Some(200), target), 300);
+    }
+
+    #[test]
+    fn deadband_skips_small_changes() {
+        let config = Config {
+            dynamic_fee_deadband: 10,
+            dynamic_fee_htlc_max_deadband: 0.1,
+            ..Config::default()
+        };
+        let last = ChannelPolicy {
+            fee_ppm: 100,
+            base_fee_msat: Some(1000),
+            htlc_min_msat: Some(0),
+            htlc_max_msat: 1_000_000,
+        };
+        let policy = |fee_ppm, base_fee_msat, htlc_max_msat| ChannelPolicy {
+            fee_ppm,
+            base_fee_msat,
+            htlc_min_msat: None,
+            htlc_max_msat,
+        };
+
+        let default = Config::default();
+        assert!(within_deadband(
+            &last,
+            &policy(100, None, 1_000_000),
+            &default
+        ));
+        assert!(!within_deadband(
+            &last,
+            &policy(101, None, 1_000_000),
+            &default
+        ));
+        assert!(within_deadband(&last, &policy(110, None, 950_000), &config));
+        assert!(!within_deadband(
+            &last,
+            &policy(120, None, 1_000_000),
+            &config
+        ));
+        assert!(!within_deadband(
+            &last,
+            &policy(100, None, 800_000),
+            &config
+        ));
+        assert!(within_deadband(
+            &last,
+            &policy(100, Some(1000), 1_000_000),
+            &config
+        ));
+        assert!(!within_deadband(
+            &last,
+            &policy(100, Some(0), 1_000_000),
+            &config
+        ));
+    }
+
+    #[test]
+    fn htlc_min_raised_when_depleted_or_flooded() {
+        let config = Config {
+            dynamic_htlc_min: true,
+            dynamic_htlc_min_normal: 1,
+            dynamic_htlc_min_raised: 5_000,
+            dynamic_htlc_min_depleted: 0.05,
+            dynamic_htlc_min_dust_limit: 10,
+            ..Config::default()
+        };
+        let healthy = test_channel(100_000, 50_000);
+        let depleted = test_channel(100_000, 1_000);
+
+        assert_eq!(calculate_htlc_min(&healthy, &config, 0, 45_000), Some(1));
+        assert_eq!(
+            calculate_htlc_min(&healthy, &config, 10, 45_000),
+            Some(5_000)
+        );
+        assert_eq!(calculate_htlc_min(&depleted, &config, 0, 900), Some(900));
+        assert_eq!(
+            calculate_htlc_min(&depleted, &Config::default(), 0, 900),
+            None
+        );
+    }
+
+    #[test]
+    fn base_fee_follows_balance() {
+        let config = Config {
+            dynamic_base_fee: true,
+            dynamic_base_fee_min: 0,
+            dynamic_base_fee_max: 1000,
+            ..Config::default()
+        };
+
+        assert_eq!(
+            calculate_base_fee(&test_channel(1000, 900), &config),
+            Some(0)
+        );
+        assert_eq!(
+            calculate_base_fee(&test_channel(1000, 500), &config),
+            Some(500)
+        );
+        assert_eq!(
+            calculate_base_fee(&test_channel(1000, 100), &config),
+            Some(1000)
+        );
+        assert_eq!(
+            calculate_base_fee(&test_channel(1000, 500), &Config::default()),
+            None
+        );
+    }
+
     #[tokio::test]
     async fn test_list_funds() {
         let j = json!({
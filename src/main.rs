@@ -1,6 +1,8 @@
 #[macro_use]
 extern crate serde_json;
 use cln_plugin::{options, Builder, Error, Plugin};
+use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
 // Try RPC Connectivity
 use anyhow::{anyhow, Result};
@@ -8,16 +10,34 @@ use std::time::Duration;
 use tokio;
 use tokio::{task, time};
 
-use ceebalancer::{get_info, onchain_balance, set_channel_fees, Config};
+use ceebalancer::analytics;
+use ceebalancer::classify;
+use ceebalancer::events::affected_channels;
+use ceebalancer::htlc_max;
+use ceebalancer::overrides::{self, ClearOverrideParams};
+use ceebalancer::rebalancer;
+use ceebalancer::refills;
+use ceebalancer::settings::parse_configuration;
+use ceebalancer::wire::ForwardEventNotification;
+use ceebalancer::{
+    now, preview_channel_fees, set_channel_fees, set_channel_fees_for, ClnBackend, Config,
+    LightningBackend, PluginState,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    if let Some(plugin) = Builder::new((), tokio::io::stdin(), tokio::io::stdout())
+    let state = PluginState::new(ClnBackend::new());
+    if let Some(plugin) = Builder::new(tokio::io::stdin(), tokio::io::stdout())
         .option(options::ConfigOption::new(
             "dynamic-fees",
             options::Value::Boolean(false),
             "Adjust fees dynamically to try to keep channels in balance",
         ))
+        .option(options::ConfigOption::new(
+            "dynamic-fees-dry-run",
+            options::Value::Boolean(false),
+            "Compute and log channel updates without sending them",
+        ))
         .option(options::ConfigOption::new(
             "dynamic-fee-min",
             options::Value::Integer(0),
@@ -43,36 +63,340 @@ async fn main() -> Result<(), anyhow::Error> {
             options::Value::Integer(7200),
             "Update/evaluation interval",
         ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-curve",
+            options::Value::String("linear".to_string()),
+            "Fee curve: linear, sigmoid[:steepness], exponential[:exponent] or piecewise:<proportion>=<fee>,...",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-target-ratio",
+            options::Value::Integer(50),
+            "Local share of the balance (percent) the fee curve is centred on",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-target-learn-window",
+            options::Value::Integer(0),
+            "Learn each channel's target from its net flow over this many seconds (0 to disable)",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-classify-window",
+            options::Value::Integer(604800),
+            "Seconds of forwards and balance history peers are classified over",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-class-curves",
+            options::Value::String("".to_string()),
+            "Fee curve per peer class, e.g. sink=exponential:3;router=sigmoid (unset classes use dynamic-fee-curve)",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-mode",
+            options::Value::String("curve".to_string()),
+            "How fees are set: curve (from the balance) or pid (a controller steering towards dynamic-fee-target-ratio)",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-pid-kp",
+            options::Value::Integer(5),
+            "PID mode: ppm per percentage point the balance is off target",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-pid-ki",
+            options::Value::Integer(2),
+            "PID mode: ppm added per percentage point off target per update interval",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-pid-kd",
+            options::Value::Integer(0),
+            "PID mode: ppm per percentage point the balance moved since the last run",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-forward-debounce",
+            options::Value::Integer(60),
+            "Seconds to wait after a forward before re-evaluating its channels (0 disables)",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-deadband",
+            options::Value::Integer(0),
+            "Skip updates when the fee would move by this many ppm or less",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-htlc-max-deadband",
+            options::Value::Integer(0),
+            "Skip updates when htlc_max would move by this percent or less (0-100)",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-hysteresis",
+            options::Value::Integer(0),
+            "Balance change (percent of the channel) needed before leaving the current fee step",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-base-fee",
+            options::Value::Boolean(false),
+            "Manage the base fee as well as the proportional fee",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-base-fee-min",
+            options::Value::Integer(0),
+            "Base fee (msat) when the channel is local-heavy",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-base-fee-max",
+            options::Value::Integer(1000),
+            "Base fee (msat) when the channel is remote-heavy",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-htlc-min",
+            options::Value::Boolean(false),
+            "Manage htlc_minimum_msat for depleted or dust-flooded channels",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-htlc-min-normal",
+            options::Value::Integer(0),
+            "htlc_minimum_msat for healthy channels",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-htlc-min-raised",
+            options::Value::Integer(1_000_000),
+            "htlc_minimum_msat for depleted or dust-flooded channels",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-htlc-min-depleted",
+            options::Value::Integer(5),
+            "Local balance (percent of the channel) below which htlc_min is raised",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-htlc-min-dust",
+            options::Value::Integer(100_000),
+            "Forwards smaller than this (msat) count as dust",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-htlc-min-dust-limit",
+            options::Value::Integer(100),
+            "Dust forwards per update interval that cause htlc_min to be raised",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-htlc-max-ladder",
+            options::Value::String(
+                htlc_max::DEFAULT_LADDER
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<String>>()
+                    .join(","),
+            ),
+            "Comma separated msat buckets htlc_max is rounded to",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-htlc-max-ratio",
+            options::Value::Integer(90),
+            "Percent of the rounded balance advertised as htlc_max",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-htlc-max-rounding",
+            options::Value::String("down".to_string()),
+            "How the balance is rounded onto the ladder: down, nearest or none",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-htlc-max-source",
+            options::Value::String("total".to_string()),
+            "Balance htlc_max is based on: total or spendable (what we can send)",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-htlc-max-reserve",
+            options::Value::Integer(1),
+            "Channel reserve (percent of the channel) subtracted in spendable mode if the node doesn't report it",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-privacy",
+            options::Value::Boolean(false),
+            "Obfuscate the balance information leaked through fees and htlc_max",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-privacy-jitter",
+            options::Value::Integer(10),
+            "Maximum random fee adjustment in privacy mode (percent of the fee)",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-privacy-htlc-max-step",
+            options::Value::Integer(3),
+            "Only every Nth htlc_max bucket is used in privacy mode",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-privacy-delay",
+            options::Value::Integer(600),
+            "Maximum random delay (seconds) before each channel update in privacy mode",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-competitors",
+            options::Value::Boolean(false),
+            "Cap fees relative to what other channels to the same peer charge",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-competitor-percentile",
+            options::Value::Integer(75),
+            "Percentile of the competing fees (0-100) our fee is capped at",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-competitor-min-count",
+            options::Value::Integer(3),
+            "Competing channels needed before the cap applies",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-report-windows",
+            options::Value::String("1d,7d,30d".to_string()),
+            "Windows ceebalancer-report aggregates forwards over (s, m, h or d)",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-fee-refill-floor-window",
+            options::Value::Integer(0),
+            "Keep fees above what the latest refill within this many seconds cost (0 to disable)",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-rebalance",
+            options::Value::Boolean(false),
+            "Rebalance overfull into depleted channels with circular payments on a schedule",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-rebalance-interval",
+            options::Value::Integer(21600),
+            "Seconds between scheduled rebalances",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-rebalance-amount",
+            options::Value::Integer(100_000_000),
+            "Most msat moved per channel pair in one rebalance",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-rebalance-budget",
+            options::Value::Integer(50),
+            "Most a rebalance may cost, in percent of the fee the depleted channel charges",
+        ))
+        .option(options::ConfigOption::new(
+            "dynamic-rebalance-max-pairs",
+            options::Value::Integer(3),
+            "Channel pairs rebalanced per run",
+        ))
         .rpcmethod(
             "ceebalancer-adjust",
             "Manually triggers an adjustment run",
             adjust_handler,
         )
+        .rpcmethod(
+            "ceebalancer-preview",
+            "Shows the current and proposed policy for each channel without applying it",
+            preview_handler,
+        )
+        .rpcmethod(
+            "ceebalancer-status",
+            "Shows the effective configuration, run timings and per-channel state",
+            status_handler,
+        )
+        .rpcmethod(
+            "ceebalancer-report",
+            "Shows forwarding volume, fees earned and failure rates per channel, optionally over other windows (e.g. windows=1h,1d)",
+            report_handler,
+        )
+        .rpcmethod(
+            "ceebalancer-classify",
+            "Labels each peer as sink, source, router or dormant from its forwards and balance history, optionally over another window (e.g. window=30d)",
+            classify_handler,
+        )
+        .rpcmethod(
+            "ceebalancer-rebalance",
+            "Moves liquidity from overfull to depleted channels with circular payments",
+            rebalance_handler,
+        )
+        .rpcmethod(
+            "ceebalancer-refills",
+            "Lists the circular payments that refilled channels, what they cost and whether the channel earns that back",
+            refills_handler,
+        )
+        .rpcmethod(
+            "ceebalancer-setconfig",
+            "Changes options at runtime (e.g. dynamic-fee-max=2000), with persist=true to keep them across restarts",
+            set_config_handler,
+        )
+        .rpcmethod(
+            "ceebalancer-setoverride",
+            "Sets the policy for a channel or peer (id, min, max, fixed_fee, exclude, curve)",
+            set_override_handler,
+        )
+        .rpcmethod(
+            "ceebalancer-clearoverride",
+            "Removes the policy override for a channel or peer",
+            clear_override_handler,
+        )
+        .rpcmethod(
+            "ceebalancer-listoverrides",
+            "Lists the policy overrides",
+            list_overrides_handler,
+        )
         .subscribe("forward_event", forward_handler)
-        .start()
+        .configure()
         .await?
     {
-        let config = load_configuration(&plugin).unwrap();
-
-        if config.dynamic_fees {
-            task::spawn(async move {
-                loop {
-                    time::sleep(Duration::from_secs(
-                        config.dynamic_fee_update_interval.try_into().unwrap(),
-                    ))
-                    .await;
-                    log::info!("Initiating dynamic fee adjustment");
-                    match set_channel_fees(config.clone()).await {
-                        Ok(_) => {
-                            log::debug!("Success");
-                        }
-                        Err(err) => {
-                            log::warn!("Error in set channel fees.  Proceeding: {:?}", err);
-                        }
-                    };
+        let configuration = plugin.configuration();
+        state
+            .backend
+            .set_rpc_path(Path::new(&configuration.lightning_dir).join(&configuration.rpc_file))
+            .await;
+        if let Err(e) = state.initialize(&configuration.lightning_dir) {
+            log::warn!("Unable to open the store, history will not persist: {:?}", e);
+        }
+        if let Err(e) = load_configuration(|name| plugin.option(name), &state) {
+            // Answering init with a disable leaves lightningd running without
+            // us rather than with a plugin that would set nonsense fees
+            log::error!("Refusing to start: {}", e);
+            return plugin.disable(&e.to_string()).await;
+        }
+        let plugin = plugin.start(state).await?;
+        let state = plugin.state().clone();
+
+        task::spawn(async move {
+            loop {
+                let interval = Config::current()
+                    .dynamic_fee_update_interval
+                    .try_into()
+                    .unwrap();
+                state.status.schedule_next(now() + interval);
+                tokio::select! {
+                    _ = time::sleep(Duration::from_secs(interval)) => {}
+                    // Start over with the new interval
+                    _ = state.reconfigured.notified() => continue,
                 }
-            });
-        };
+                let config = Config::current();
+                if !config.dynamic_fees {
+                    continue;
+                }
+                log::info!("Initiating dynamic fee adjustment");
+                match set_channel_fees(config.clone(), &state).await {
+                    Ok(_) => {
+                        log::debug!("Success");
+                    }
+                    Err(err) => {
+                        log::warn!("Error in set channel fees.  Proceeding: {:?}", err);
+                    }
+                };
+            }
+        });
+
+        let state = plugin.state().clone();
+        task::spawn(async move {
+            loop {
+                let interval = Config::current().dynamic_rebalance_interval.max(1) as u64;
+                tokio::select! {
+                    _ = time::sleep(Duration::from_secs(interval)) => {}
+                    // Start over with the new interval
+                    _ = state.reconfigured.notified() => continue,
+                }
+                let config = Config::current();
+                if !config.dynamic_rebalance {
+                    continue;
+                }
+                log::info!("Initiating rebalance");
+                if let Err(err) = rebalancer::rebalance(config, &state).await {
+                    log::warn!("Error rebalancing.  Proceeding: {:?}", err);
+                }
+            }
+        });
 
         plugin.join().await
     } else {
@@ -80,110 +404,238 @@ async fn main() -> Result<(), anyhow::Error> {
     }
 }
 
-fn load_configuration(plugin: &Plugin<()>) -> Result<Arc<Config>, Error> {
-    let c = Config::default();
-
-    let dynamic_fees = match plugin.option("dynamic-fees") {
-        Some(options::Value::Boolean(false)) => {
-            log::debug!("`dynamic-fees` option is set to false.  Disabling");
-            false
-        }
-        Some(options::Value::Boolean(true)) => {
-            log::debug!("`dynamic-fees` option is set to true.  Enabling.");
-            true
-        }
-        None => {
-            log::info!("Missing 'dynamic-fees' option.  Disabling.");
-            false
-        }
-        Some(o) => return Err(anyhow!("dynamic-fees is not a valid boolean: {:?}.", o)),
-    };
-    let dynamic_fee_min = match plugin.option("dynamic-fee-min") {
-        Some(options::Value::Integer(i)) => i,
-        None => {
-            log::info!("Missing 'dynamic-fee-min' option.  Using default.");
-            c.dynamic_fee_min
-        }
-        Some(o) => return Err(anyhow!("dynamic-fee-min is not a valid integer: {:?}.", o)),
-    };
-    let dynamic_fee_max = match plugin.option("dynamic-fee-max") {
-        Some(options::Value::Integer(i)) => i,
-        None => {
-            log::info!("Missing 'dynamic-fee-max' option.  Using default.");
-            c.dynamic_fee_min
-        }
-        Some(o) => return Err(anyhow!("dynamic-fee-max is not a valid integer: {:?}.", o)),
-    };
-    let dynamic_fee_threshold = match plugin.option("dynamic-fee-threshold") {
-        Some(options::Value::Integer(i)) => 100.0 / i as f32,
-        None => {
-            log::info!("Missing 'dynamic-fee-threshold' option.  Using default.");
-            c.dynamic_fee_threshold
-        }
-        Some(o) => {
-            return Err(anyhow!(
-                "dynamic-fee-threshold is not a valid integer: {:?}.",
-                o
-            ))
-        }
-    };
-    let dynamic_fee_width = match plugin.option("dynamic-fee-width") {
-        Some(options::Value::Integer(i)) => i,
-        None => {
-            log::info!("Missing 'dynamic-fee-width' option.  Using default.");
-            c.dynamic_fee_width
-        }
-        Some(o) => {
-            return Err(anyhow!(
-                "dynamic-fee-width is not a valid integer: {:?}.",
-                o
-            ))
-        }
-    };
-    let dynamic_fee_update_interval = match plugin.option("dynamic-fee-interval") {
-        Some(options::Value::Integer(i)) => i,
-        None => {
-            log::info!("Missing 'dynamic-fee-interval' option.  Using default.");
-            c.dynamic_fee_update_interval
-        }
-        Some(o) => {
-            return Err(anyhow!(
-                "dynamic-fee-interval is not a valid integer: {:?}.",
-                o
-            ))
-        }
-    };
-
-    let dynamic_fee_threshold = 0.2;
-    let dynamic_fee_width = 50;
-
-    Config {
-        dynamic_fees,
-        dynamic_fee_min,
-        dynamic_fee_max,
-        dynamic_fee_threshold,
-        dynamic_fee_width,
-        dynamic_fee_update_interval,
-    }
-    .make_current();
+/// Reads the plugin options, with any `ceebalancer-setconfig` changes on top,
+/// and makes the result the current config.
+fn load_configuration(
+    option: impl Fn(&str) -> Option<options::Value>,
+    state: &PluginState,
+) -> Result<Arc<Config>, Error> {
+    let runtime = runtime_options(&option, &state.store.config_options())?;
+    parse_configuration(|name| runtime.get(name).cloned().or_else(|| option(name)))?.make_current();
     log::info!("Configuration loaded: {:?}", Config::current());
     Ok(Config::current())
 }
 
-async fn test_get_info(_plugin: &Plugin<()>) -> Result<(), Error> {
+/// Converts options set over RPC to the type lightningd would have given us,
+/// rejecting names we don't know.
+fn runtime_options(
+    option: impl Fn(&str) -> Option<options::Value>,
+    values: &serde_json::Map<String, serde_json::Value>,
+) -> Result<HashMap<String, options::Value>, Error> {
+    let mut runtime = HashMap::new();
+    for (name, value) in values {
+        let value = match (option(name), value) {
+            (None, _) => return Err(anyhow!("Unknown option {}", name)),
+            (Some(options::Value::Integer(_)), serde_json::Value::Number(n)) if n.is_i64() => {
+                options::Value::Integer(n.as_i64().unwrap())
+            }
+            (Some(options::Value::Integer(_)), serde_json::Value::String(s)) => {
+                options::Value::Integer(
+                    s.parse()
+                        .map_err(|_| anyhow!("{} is not a valid integer: {:?}.", name, s))?,
+                )
+            }
+            (Some(options::Value::Boolean(_)), serde_json::Value::Bool(b)) => {
+                options::Value::Boolean(*b)
+            }
+            (Some(options::Value::Boolean(_)), serde_json::Value::String(s)) => {
+                options::Value::Boolean(
+                    s.parse()
+                        .map_err(|_| anyhow!("{} is not a valid boolean: {:?}.", name, s))?,
+                )
+            }
+            (Some(options::Value::String(_)), serde_json::Value::String(s)) => {
+                options::Value::String(s.clone())
+            }
+            (Some(options::Value::String(_)), v) => options::Value::String(v.to_string()),
+            (Some(_), v) => return Err(anyhow!("{} has the wrong type: {}.", name, v)),
+        };
+        runtime.insert(name.clone(), value);
+    }
+    Ok(runtime)
+}
+
+async fn test_get_info(plugin: &Plugin<PluginState>) -> Result<(), Error> {
     log::debug!("Testing getinfo as a sanity check");
-    let info = get_info().await.unwrap();
+    let info = plugin.state().backend.get_info().await?;
     log::info!("Got info: {}", info);
     Ok(())
 }
 
-async fn forward_handler(_p: Plugin<()>, v: serde_json::Value) -> Result<(), Error> {
+async fn forward_handler(p: Plugin<PluginState>, v: serde_json::Value) -> Result<(), Error> {
     log::debug!("Got a forward notification: {}", v);
+    let notification: ForwardEventNotification = serde_json::from_value(v)?;
+    let config = Config::current();
+    if config.dynamic_htlc_min {
+        p.state().dust.record(
+            &notification.forward_event,
+            config.dynamic_htlc_min_dust.max(0) as u64,
+            now(),
+        );
+    }
+
+    let channels = affected_channels(&notification.forward_event);
+    if channels.is_empty() || !config.dynamic_fees || config.dynamic_fee_forward_debounce <= 0 {
+        return Ok(());
+    }
+
+    let state = p.state().clone();
+    if state.debouncer.push(channels) {
+        task::spawn(async move {
+            time::sleep(Duration::from_secs(
+                config.dynamic_fee_forward_debounce as u64,
+            ))
+            .await;
+            let channels = state.debouncer.drain();
+            log::info!("Re-evaluating channels after forwards: {:?}", channels);
+            if let Err(err) = set_channel_fees_for(config.clone(), &state, &channels).await {
+                log::warn!("Error in forward triggered adjustment: {:?}", err);
+            }
+        });
+    }
     Ok(())
 }
 
-async fn adjust_handler(p: Plugin<()>, _v: serde_json::Value) -> Result<serde_json::Value, Error> {
-    let config = load_configuration(&p).unwrap();
-    set_channel_fees(config.clone()).await.unwrap();
+async fn adjust_handler(
+    p: Plugin<PluginState>,
+    _v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let config = Config::current();
+    if config.dynamic_fees_dry_run {
+        let plans = preview_channel_fees(config, p.state()).await?;
+        return Ok(json!({ "channels": plans }));
+    }
+    set_channel_fees(config.clone(), p.state()).await?;
+    Ok(json!("Success"))
+}
+
+async fn preview_handler(
+    p: Plugin<PluginState>,
+    _v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let config = Config::current();
+    let plans = preview_channel_fees(config, p.state()).await?;
+    Ok(json!({ "channels": plans }))
+}
+
+async fn status_handler(
+    p: Plugin<PluginState>,
+    _v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let config = Config::current();
+    let report = p.state().status.report(&config, &p.state().store);
+    Ok(serde_json::to_value(report)?)
+}
+
+async fn report_handler(
+    p: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let windows = match v.get("windows").or_else(|| v.get(0)) {
+        None => Config::current().dynamic_fee_report_windows.clone(),
+        Some(serde_json::Value::String(s)) => {
+            analytics::parse_windows(s).map_err(|e| anyhow!("windows: {}", e))?
+        }
+        Some(o) => return Err(anyhow!("windows is not a valid string: {}.", o)),
+    };
+    let windows = analytics::flow_report(p.state(), &windows).await?;
+    Ok(json!({ "windows": windows }))
+}
+
+async fn classify_handler(
+    p: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let window = match v.get("window").or_else(|| v.get(0)) {
+        None => Config::current().dynamic_fee_classify_window.max(1) as u64,
+        Some(serde_json::Value::String(s)) => match analytics::parse_windows(s) {
+            Ok(windows) if windows.len() == 1 => windows[0],
+            Ok(_) => return Err(anyhow!("window: expected a single window")),
+            Err(e) => return Err(anyhow!("window: {}", e)),
+        },
+        Some(o) => return Err(anyhow!("window is not a valid string: {}.", o)),
+    };
+    let peers = classify::classification_report(p.state(), window).await?;
+    Ok(json!({ "window": window, "peers": peers }))
+}
+
+async fn rebalance_handler(
+    p: Plugin<PluginState>,
+    _v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let outcomes = rebalancer::rebalance(Config::current(), p.state()).await?;
+    Ok(json!({ "rebalances": outcomes }))
+}
+
+async fn refills_handler(
+    p: Plugin<PluginState>,
+    _v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let refills = refills::refill_report(p.state()).await?;
+    Ok(json!({ "refills": refills }))
+}
+
+async fn set_config_handler(
+    p: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let mut values = match v {
+        serde_json::Value::Object(values) => values,
+        _ => return Err(anyhow!("Invalid parameters (use `lightning-cli -k`)")),
+    };
+    let persist = match values.remove("persist") {
+        None | Some(serde_json::Value::Bool(false)) => false,
+        Some(serde_json::Value::Bool(true)) => true,
+        Some(o) => return Err(anyhow!("persist is not a valid boolean: {}.", o)),
+    };
+    // Parse everything before storing anything, so a bad value changes nothing
+    let mut merged = p.state().store.config_options();
+    merged.extend(values.clone());
+    let runtime = runtime_options(|name| p.option(name), &merged)?;
+    let config = parse_configuration(|name| runtime.get(name).cloned().or_else(|| p.option(name)))?;
+
+    p.state().store.set_config_options(values, persist)?;
+    config.make_current();
+    // Both schedulers wait on this.  One that's busy running picks the
+    // change up when it next reads the config
+    p.state().reconfigured.notify_waiters();
+    log::info!("Configuration changed: {:?}", Config::current());
+    Ok(json!({ "config": *Config::current() }))
+}
+
+async fn set_override_handler(
+    p: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let params = overrides::parse_set_params(v, &Config::current())?;
+    p.state()
+        .store
+        .set_override(&params.id, Some(params.policy.clone()))?;
+    log::info!(
+        "Override set (ID: {}, Policy: {:?})",
+        params.id,
+        params.policy
+    );
+    Ok(json!({ params.id: params.policy }))
+}
+
+async fn clear_override_handler(
+    p: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let params: ClearOverrideParams = serde_json::from_value(v)
+        .map_err(|e| anyhow!("Invalid parameters (use `lightning-cli -k`): {}", e))?;
+    if p.state().store.get_override(&params.id).is_none() {
+        return Err(anyhow!("No override for {}", params.id));
+    }
+    p.state().store.set_override(&params.id, None)?;
+    log::info!("Override cleared (ID: {})", params.id);
     Ok(json!("Success"))
 }
+
+async fn list_overrides_handler(
+    p: Plugin<PluginState>,
+    _v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    Ok(json!({ "overrides": p.state().store.overrides() }))
+}
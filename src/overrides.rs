@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::{wire, Config, FeeCurve};
+
+/// Policy attached to a single channel (by short_channel_id) or to every
+/// channel with a peer (by peer_id).  Unset fields fall back to the global
+/// `Config`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Override {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fixed_fee: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fixed_base_fee: Option<u64>,
+    #[serde(default)]
+    pub exclude: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub curve: Option<FeeCurve>,
+    /// Local share of the balance, in percent, the curve is centred on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<u32>,
+}
+
+impl Override {
+    /// The global config with this override's min, max, curve and target
+    /// layered on top.
+    pub fn apply(&self, config: &Config) -> Config {
+        let mut c = config.clone();
+        if let Some(min) = self.min {
+            c.dynamic_fee_min = min;
+        }
+        if let Some(max) = self.max {
+            c.dynamic_fee_max = max;
+        }
+        if let Some(curve) = &self.curve {
+            c.dynamic_fee_curve = curve.clone();
+        }
+        if let Some(target) = self.target {
+            c.dynamic_fee_target_ratio = target as f64 / 100.0;
+        }
+        c
+    }
+}
+
+#[derive(Debug)]
+pub struct SetOverrideParams {
+    pub id: String,
+    pub policy: Override,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClearOverrideParams {
+    pub id: String,
+}
+
+/// Short channel ids look like `123x4x0`, peer ids are 33 byte hex pubkeys.
+pub fn validate_id(id: &str) -> Result<(), Error> {
+    let is_short_channel_id = id.split('x').count() == 3
+        && id
+            .split('x')
+            .all(|p| !p.is_empty() && p.parse::<u64>().is_ok());
+    let is_peer_id = id.len() == 66 && id.chars().all(|c| c.is_ascii_hexdigit());
+    if is_short_channel_id || is_peer_id {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} is neither a short_channel_id nor a peer_id",
+            id
+        ))
+    }
+}
+
+/// Splits the `id` off the rest of the parameters.  `deny_unknown_fields`
+/// doesn't work through `#[serde(flatten)]`, so the policy is deserialized
+/// on its own, which rejects misspelled keys instead of storing an empty
+/// override.  A lone `min` or `max` is checked against the other end of
+/// `config`'s range.
+pub fn parse_set_params(v: serde_json::Value, config: &Config) -> Result<SetOverrideParams, Error> {
+    let mut map = match v {
+        serde_json::Value::Object(map) => map,
+        _ => return Err(anyhow!("Invalid override (use `lightning-cli -k`)")),
+    };
+    let id = match map.remove("id") {
+        Some(serde_json::Value::String(id)) => id,
+        _ => return Err(anyhow!("Invalid override: missing id")),
+    };
+    let policy: Override = serde_json::from_value(serde_json::Value::Object(map))
+        .map_err(|e| anyhow!("Invalid override: {}", e))?;
+    let params = SetOverrideParams { id, policy };
+    validate_id(&params.id)?;
+    let effective = params.policy.apply(config);
+    if effective.dynamic_fee_min > effective.dynamic_fee_max {
+        return Err(anyhow!(
+            "Override min {} is greater than max {}",
+            effective.dynamic_fee_min,
+            effective.dynamic_fee_max
+        ));
+    }
+    if let Some(target) = params.policy.target {
+        if !(1..=99).contains(&target) {
+            return Err(anyhow!("Override target {} is not 1 to 99 percent", target));
+        }
+    }
+    Ok(params)
+}
+
+/// Picks the override for a channel; a short_channel_id override wins over
+/// one for the peer.
+pub fn lookup<F>(channel: &wire::Channel, get: F) -> Option<Override>
+where
+    F: Fn(&str) -> Option<Override>,
+{
+    channel
+        .short_channel_id
+        .as_deref()
+        .and_then(&get)
+        .or_else(|| get(&channel.peer_id))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_override_params() {
+        let params = parse_set_params(
+            json!({
+                "id": "123x4x0",
+                "min": 10,
+                "max": 200,
+                "curve": "sigmoid:5",
+                "target": 80
+            }),
+            &Config::default(),
+        )
+        .unwrap();
+        assert_eq!(params.id, "123x4x0");
+        assert_eq!(params.policy.curve, Some(FeeCurve::Sigmoid(5.0)));
+        assert!(!params.policy.exclude);
+
+        let config = Config::default();
+        let applied = params.policy.apply(&config);
+        assert_eq!(applied.dynamic_fee_min, 10);
+        assert_eq!(applied.dynamic_fee_max, 200);
+        assert_eq!(applied.dynamic_fee_target_ratio, 0.8);
+
+        assert!(parse_set_params(json!({"id": "nonsense", "exclude": true}), &config).is_err());
+        assert!(parse_set_params(json!({"id": "1x1x1", "min": 5, "max": 1}), &config).is_err());
+        assert!(parse_set_params(json!({"id": "1x1x1", "target": 100}), &config).is_err());
+        assert!(parse_set_params(json!(["1x1x1", 0]), &config).is_err());
+        assert!(parse_set_params(json!({"min": 10}), &config).is_err());
+    }
+
+    #[test]
+    fn unknown_override_keys_are_rejected() {
+        let config = Config::default();
+        assert!(parse_set_params(json!({"id": "1x1x1", "fixedfee": 10}), &config).is_err());
+        assert!(parse_set_params(json!({"id": "1x1x1", "fixed_fee": 10}), &config).is_ok());
+    }
+
+    #[test]
+    fn lone_min_or_max_is_checked_against_the_config() {
+        let config = Config {
+            dynamic_fee_min: 100,
+            dynamic_fee_max: 1000,
+            ..Config::default()
+        };
+        assert!(parse_set_params(json!({"id": "1x1x1", "min": 2000}), &config).is_err());
+        assert!(parse_set_params(json!({"id": "1x1x1", "max": 50}), &config).is_err());
+        assert!(parse_set_params(json!({"id": "1x1x1", "min": 1000}), &config).is_ok());
+        assert!(parse_set_params(json!({"id": "1x1x1", "max": 100}), &config).is_ok());
+    }
+
+    #[test]
+    fn channel_override_wins_over_peer() {
+        let channel: wire::Channel = serde_json::from_value(json!({
+            "peer_id": "039b9e260863e6d8735325b286931d73be9f8e766970ad4fe1cbcc470cd8964635",
+            "connected": true,
+            "state": "CHANNELD_NORMAL",
+            "short_channel_id": "206x5x0",
+            "our_amount_msat": "4000000000msat",
+            "amount_msat": "4000000000msat",
+            "funding_txid": "724ee70bc1670368c3db3c2ebed30d00fa595774356cebf509196c68a471ca91",
+            "funding_output": 0
+        }))
+        .unwrap();
+
+        let peer = Override {
+            fixed_fee: Some(0),
+            ..Override::default()
+        };
+        let chan = Override {
+            exclude: true,
+            ..Override::default()
+        };
+
+        let found = lookup(&channel, |id| match id {
+            "206x5x0" => Some(chan.clone()),
+            "039b9e260863e6d8735325b286931d73be9f8e766970ad4fe1cbcc470cd8964635" => {
+                Some(peer.clone())
+            }
+            _ => None,
+        });
+        assert_eq!(found, Some(chan.clone()));
+
+        let found = lookup(&channel, |id| match id {
+            "039b9e260863e6d8735325b286931d73be9f8e766970ad4fe1cbcc470cd8964635" => {
+                Some(peer.clone())
+            }
+            _ => None,
+        });
+        assert_eq!(found, Some(peer));
+    }
+}
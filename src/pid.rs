@@ -0,0 +1,246 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{remote_proportion, wire, Config};
+
+/// How the fee target is worked out.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FeeMode {
+    /// Straight from the balance, along `dynamic-fee-curve`
+    #[default]
+    Curve,
+    /// Moved a little each run by a controller steering the balance towards
+    /// `dynamic-fee-target-ratio`
+    Pid,
+}
+
+impl FromStr for FeeMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<FeeMode, Error> {
+        match s.trim().to_lowercase().as_str() {
+            "curve" => Ok(FeeMode::Curve),
+            "pid" => Ok(FeeMode::Pid),
+            _ => Err(anyhow!("Unknown fee mode: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for FeeMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FeeMode::Curve => write!(f, "curve"),
+            FeeMode::Pid => write!(f, "pid"),
+        }
+    }
+}
+
+impl Serialize for FeeMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// Controller state for one channel, kept in the store between runs.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct PidState {
+    pub timestamp: u64,
+    /// Balance error at that run, see `balance_error`.
+    pub error: f64,
+    /// The integral term, in ppm.
+    pub integral: f64,
+}
+
+/// How many percentage points of the channel we are short of the target
+/// local share.  Negative while we hold more than the target.
+pub fn balance_error(channel: &wire::Channel, config: &Config) -> f64 {
+    (remote_proportion(channel) - (1.0 - config.dynamic_fee_target_ratio)) * 100.0
+}
+
+/// One controller step from `previous` to `now`, returning the fee in ppm
+/// (before rounding) and the state to keep.
+pub fn step(error: f64, previous: &PidState, now: u64, config: &Config) -> (f64, PidState) {
+    let min = config.dynamic_fee_min as f64;
+    let max = config.dynamic_fee_max as f64;
+    // Forwards trigger runs in between the scheduled ones, so the integral
+    // is weighted by time.  A longer gap, e.g. a restart, counts as a single
+    // interval.
+    let interval = config.dynamic_fee_update_interval.max(1) as f64;
+    let dt = (now.saturating_sub(previous.timestamp) as f64 / interval).min(1.0);
+    // Clamped, so a channel that sat at a limit for a while doesn't take as
+    // long again to come off it.  Not with `f64::clamp`, which panics on an
+    // override that puts min above max
+    let integral = (previous.integral + config.dynamic_fee_pid_ki as f64 * error * dt)
+        .max(min)
+        .min(max);
+    let output = config.dynamic_fee_pid_kp as f64 * error
+        + integral
+        + config.dynamic_fee_pid_kd as f64 * (error - previous.error);
+    (
+        output.max(min).min(max),
+        PidState {
+            timestamp: now,
+            error,
+            integral,
+        },
+    )
+}
+
+/// The fee for `channel` in PID mode, rounded down to `dynamic_fee_width`,
+/// and the state to keep for the next run.  Without a previous state the
+/// controller starts out at `start_fee`, so switching modes doesn't jump the
+/// fee.
+pub fn pid_fee(
+    channel: &wire::Channel,
+    config: &Config,
+    previous: Option<&PidState>,
+    start_fee: u32,
+    now: u64,
+) -> (u32, PidState) {
+    let error = balance_error(channel, config);
+    let previous = match previous {
+        Some(previous) => previous.clone(),
+        None => PidState {
+            timestamp: now,
+            error,
+            integral: start_fee as f64 - config.dynamic_fee_pid_kp as f64 * error,
+        },
+    };
+    let (output, state) = step(error, &previous, now, config);
+    let width = config.dynamic_fee_width.max(1) as f64;
+    (((output / width).floor() * width) as u32, state)
+}
+
+/// Winds the integral back to what the fee that was actually published
+/// implies.  Caps, floors, jitter and rounding can all move the fee off the
+/// controller's output, and an integral left to grow past them would take
+/// as long to unwind once they let go.
+pub fn back_calculate(
+    state: &PidState,
+    previous: Option<&PidState>,
+    published: u32,
+    config: &Config,
+) -> PidState {
+    let previous_error = previous.map_or(state.error, |p| p.error);
+    let integral = published as f64
+        - config.dynamic_fee_pid_kp as f64 * state.error
+        - config.dynamic_fee_pid_kd as f64 * (state.error - previous_error);
+    PidState {
+        integral: integral
+            .max(config.dynamic_fee_min as f64)
+            .min(config.dynamic_fee_max as f64),
+        ..state.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn channel(ours: u64) -> wire::Channel {
+        wire::Channel {
+            short_channel_id: Some("1x1x1".to_string()),
+            ..crate::test_channel(1000, ours)
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            dynamic_fee_min: 0,
+            dynamic_fee_max: 1000,
+            dynamic_fee_width: 1,
+            dynamic_fee_update_interval: 100,
+            dynamic_fee_target_ratio: 0.5,
+            dynamic_fee_pid_kp: 5,
+            dynamic_fee_pid_ki: 2,
+            dynamic_fee_pid_kd: 0,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn starts_where_the_fee_was() {
+        let config = config();
+        let (fee, state) = pid_fee(&channel(250), &config, None, 400, 1000);
+        assert_eq!(fee, 400);
+        assert_eq!(state.error, 25.0);
+        assert_eq!(state.integral, 275.0);
+    }
+
+    #[test]
+    fn integral_keeps_raising_a_depleted_channel() {
+        let config = config();
+        let depleted = channel(250);
+        let (_, first) = pid_fee(&depleted, &config, None, 400, 1000);
+        let (fee, second) = pid_fee(&depleted, &config, Some(&first), 400, 1100);
+        assert_eq!(second.integral, 325.0);
+        assert_eq!(fee, 450);
+
+        // Half an interval later only half the integral step is added
+        let (fee, _) = pid_fee(&depleted, &config, Some(&second), 400, 1150);
+        assert_eq!(fee, 475);
+
+        // Back at the target the proportional term is gone and the fee
+        // settles on the integral
+        let (fee, balanced) = pid_fee(&channel(500), &config, Some(&second), 400, 1200);
+        assert_eq!(fee, 325);
+        assert_eq!(balanced.integral, 325.0);
+    }
+
+    #[test]
+    fn integral_is_clamped_to_the_fee_range() {
+        let config = Config {
+            dynamic_fee_max: 500,
+            ..config()
+        };
+        let previous = PidState {
+            timestamp: 0,
+            error: 50.0,
+            integral: 490.0,
+        };
+        let (fee, state) = pid_fee(&channel(0), &config, Some(&previous), 0, 100);
+        assert_eq!(fee, 500);
+        assert_eq!(state.integral, 500.0);
+
+        let (fee, _) = pid_fee(&channel(1000), &config, Some(&state), 0, 200);
+        assert_eq!(fee, 150);
+    }
+
+    #[test]
+    fn min_only_override_above_the_max_does_not_panic() {
+        let config = crate::overrides::Override {
+            min: Some(2000),
+            ..crate::overrides::Override::default()
+        }
+        .apply(&config());
+        let (fee, state) = pid_fee(&channel(250), &config, None, 400, 1000);
+        assert_eq!(fee, 1000);
+        assert_eq!(state.integral, 1000.0);
+
+        let state = back_calculate(&state, None, fee, &config);
+        assert_eq!(state.integral, 1000.0);
+    }
+
+    #[test]
+    fn integral_follows_the_published_fee() {
+        let config = config();
+        let depleted = channel(250);
+        let (_, first) = pid_fee(&depleted, &config, None, 400, 1000);
+        let (fee, second) = pid_fee(&depleted, &config, Some(&first), 400, 1100);
+        assert_eq!(fee, 450);
+
+        // A competitor cap held the fee at 300, so the integral is what
+        // gives 300 with the proportional term on top
+        let capped = back_calculate(&second, Some(&first), 300, &config);
+        assert_eq!(capped.integral, 175.0);
+        assert_eq!(capped.error, second.error);
+
+        // Once the cap lets go the fee climbs from there rather than
+        // jumping to where the integral would have wound up
+        let (fee, _) = pid_fee(&depleted, &config, Some(&capped), 400, 1200);
+        assert_eq!(fee, 350);
+    }
+}
@@ -1,66 +1,73 @@
-pub mod plugin_state {
+use std::path::Path;
+use std::sync::Arc;
 
-    #[derive(Clone, Debug)]
-    pub struct PluginState {
-        pub initialized: bool,
-        pub config: Config,
-    }
+use anyhow::Error;
+use tokio::sync::{Mutex, Notify};
 
-    impl PluginState {
-        pub fn initialize(&mut self) -> &mut PluginState {
-            self.initialized = true;
-            self
-        }
+use crate::backend::LightningBackend;
+use crate::cln_client::ClnBackend;
+use crate::events::{Debouncer, DustTracker};
+use crate::status::RunStatus;
+use crate::store::Store;
 
-        pub fn enable_dynamic_fees(&mut self) -> &mut PluginState {
-            self.config.dynamic_fees = true;
-            self
-        }
-    }
+/// Name of the store file, relative to the lightning dir.
+pub const STORE_FILE: &str = "ceebalancer.jsonl";
 
-    #[derive(Clone, Debug)]
-    pub struct Config {
-        pub dynamic_fees: bool
-    }
+/// State shared by the scheduler, the notification handlers and the RPC
+/// methods.  Cloning is cheap and every clone sees the same state.
+#[derive(Clone, Debug)]
+pub struct PluginState<B = ClnBackend> {
+    pub backend: B,
+    pub debouncer: Debouncer,
+    pub dust: DustTracker,
+    pub store: Store,
+    pub status: RunStatus,
+    /// Woken when the config changes so the fee and rebalance schedulers
+    /// pick up a new interval straight away.
+    pub reconfigured: Arc<Notify>,
+    /// Held while a rebalance runs, so a manual one can't overlap the
+    /// scheduled one.
+    pub rebalancing: Arc<Mutex<()>>,
+}
 
-    impl Config {
-        pub fn new() -> Config {
-            Config {
-                dynamic_fees: false
-            }
+impl<B: LightningBackend> PluginState<B> {
+    pub fn new(backend: B) -> PluginState<B> {
+        PluginState {
+            backend,
+            debouncer: Debouncer::default(),
+            dust: DustTracker::default(),
+            store: Store::default(),
+            status: RunStatus::default(),
+            reconfigured: Arc::default(),
+            rebalancing: Arc::default(),
         }
     }
-}
 
+    /// Attaches the persistent store once lightningd has told us where its
+    /// directory is.
+    pub fn initialize<P: AsRef<Path>>(&self, lightning_dir: P) -> Result<(), Error> {
+        self.store.attach(lightning_dir.as_ref().join(STORE_FILE))
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use crate::PluginState;
-    use crate::Config;
+    use super::*;
 
     #[test]
     fn it_can_be_initialized() {
-        let mut ps = PluginState {
-            initialized: false,
-            config: Config::new()
-        };
+        let dir = std::env::temp_dir().join(format!("ceebalancer-state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ps = PluginState::new(ClnBackend::new());
 
-        ps.initialize();
+        ps.initialize(&dir).unwrap();
+        ps.clone()
+            .store
+            .record_applied("1x1x1", &Default::default())
+            .unwrap();
 
-        assert!(ps.initialized);
+        assert!(dir.join(STORE_FILE).exists());
+        assert!(ps.store.channel("1x1x1").is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
-
-    #[test]
-    fn it_can_enable_dynamic_fees() {
-        let mut ps = PluginState {
-            initialized: true,
-            config: Config {
-                dynamic_fees: false
-            }
-        };
-
-        ps.enable_dynamic_fees();
-
-        assert!(ps.config.dynamic_fees);
-    }
-}
\ No newline at end of file
+}
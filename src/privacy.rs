@@ -0,0 +1,83 @@
+use std::hash::Hasher;
+use std::time::Duration;
+
+use rand::Rng;
+use siphasher::sip::SipHasher24;
+
+use crate::Config;
+
+/// Pseudo-random offset in [-1.0, 1.0] keyed on a per-node secret.  The same
+/// channel and fee always get the same offset, so the jitter doesn't change
+/// (and cause a `channel_update`) unless the underlying fee does.  SipHash
+/// over fixed bytes rather than std's `DefaultHasher`, whose algorithm may
+/// change between Rust releases and re-jitter every channel after an
+/// upgrade.
+fn keyed_offset(seed: u64, short_channel_id: &str, fee: u32) -> f64 {
+    let mut hasher = SipHasher24::new_with_keys(seed, 0);
+    hasher.write(short_channel_id.as_bytes());
+    hasher.write(&[0xff]);
+    hasher.write(&fee.to_le_bytes());
+    (hasher.finish() as f64 / u64::MAX as f64) * 2.0 - 1.0
+}
+
+/// Moves the fee by up to `dynamic_fee_privacy_jitter` of itself in either
+/// direction, staying within the configured min and max.
+pub fn jitter_fee(seed: u64, short_channel_id: &str, fee: u32, config: &Config) -> u32 {
+    let offset = keyed_offset(seed, short_channel_id, fee) * config.dynamic_fee_privacy_jitter;
+    let jittered = (fee as f64 * (1.0 + offset)).round();
+    jittered
+        .max(config.dynamic_fee_min as f64)
+        .min(config.dynamic_fee_max as f64)
+        .max(0.0) as u32
+}
+
+/// Keeps every `step`th rung of the ladder, counting down from the top so
+/// the largest bucket survives.
+pub fn coarsen_ladder(ladder: &[u64], step: i64) -> Vec<u64> {
+    let step = step.max(1) as usize;
+    let mut coarse: Vec<u64> = ladder.iter().rev().step_by(step).copied().collect();
+    coarse.reverse();
+    coarse
+}
+
+/// How long to hold a channel's update back, up to
+/// `dynamic_fee_privacy_delay` seconds.
+pub fn random_delay(config: &Config) -> Duration {
+    let max = config.dynamic_fee_privacy_delay.max(0) as u64;
+    if max == 0 {
+        return Duration::from_secs(0);
+    }
+    Duration::from_secs(rand::thread_rng().gen_range(0..=max))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn jitter_is_bounded_and_stable() {
+        let config = Config {
+            dynamic_fee_min: 0,
+            dynamic_fee_max: 1000,
+            dynamic_fee_privacy_jitter: 0.1,
+            ..Config::default()
+        };
+        for fee in [100, 400, 950] {
+            let jittered = jitter_fee(42, "123x1x0", fee, &config);
+            assert!(jittered as f64 >= fee as f64 * 0.9 - 1.0);
+            assert!(jittered as f64 <= (fee as f64 * 1.1 + 1.0).min(1000.0));
+            assert_eq!(jittered, jitter_fee(42, "123x1x0", fee, &config));
+        }
+        // Pinned, so a change of hash shows up here rather than as every
+        // channel being re-announced
+        assert_eq!(jitter_fee(42, "123x1x0", 400, &config), 366);
+    }
+
+    #[test]
+    fn coarsens_from_the_top() {
+        let ladder = [1, 2, 3, 4, 5, 6, 7];
+        assert_eq!(coarsen_ladder(&ladder, 3), vec![1, 4, 7]);
+        assert_eq!(coarsen_ladder(&ladder, 1), ladder.to_vec());
+        assert_eq!(coarsen_ladder(&ladder, 0), ladder.to_vec());
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+
+use crate::fee_strategy::recentre;
+use crate::primitives::Amount;
+use crate::{
+    calculate_fee_target, now, remote_proportion, set_channel_fees_for, targets, wire, Config,
+    LightningBackend, PluginState,
+};
+
+/// Blocks the payment has to be able to wait at our end, matching the
+/// invoice's default `min_final_cltv_expiry`.
+const FINAL_CLTV: u32 = 18;
+
+/// What happened to one pair of channels.
+#[derive(Clone, Debug, Serialize)]
+pub struct RebalanceOutcome {
+    /// Overfull channel the liquidity leaves through.
+    pub source: String,
+    /// Depleted channel it comes back in through.
+    pub target: String,
+    pub amount_msat: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_msat: Option<u64>,
+    pub max_fee_msat: u64,
+    pub status: RebalanceStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RebalanceStatus {
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+/// The local share each channel's fee aims for, by short channel id: the
+/// learned target if there is one, under the channel's override.
+pub async fn target_ratios<B: LightningBackend>(
+    channels: &[wire::Channel],
+    config: &Config,
+    state: &PluginState<B>,
+) -> HashMap<String, f64> {
+    let learned = targets::learned_targets(config, state).await;
+    channels
+        .iter()
+        .filter_map(|c| {
+            let short_channel_id = c.short_channel_id.clone()?;
+            let mut config = config.clone();
+            if let Some(target) = learned.get(&short_channel_id) {
+                config.dynamic_fee_target_ratio = *target;
+            }
+            let config = state
+                .store
+                .override_for(c)
+                .unwrap_or_default()
+                .apply(&config);
+            Some((short_channel_id, config.dynamic_fee_target_ratio))
+        })
+        .collect()
+}
+
+fn target_ratio(channel: &wire::Channel, config: &Config, targets: &HashMap<String, f64>) -> f64 {
+    channel
+        .short_channel_id
+        .as_ref()
+        .and_then(|id| targets.get(id))
+        .copied()
+        .unwrap_or(config.dynamic_fee_target_ratio)
+}
+
+/// Pairs the most overfull channels with the most depleted ones, using the
+/// same recentred remote proportion and thresholds the fee curve does, so a
+/// channel is only moved when its fee says it's off its target.
+pub fn pick_pairs(
+    channels: &[wire::Channel],
+    config: &Config,
+    targets: &HashMap<String, f64>,
+) -> Vec<(wire::Channel, wire::Channel)> {
+    let threshold = config.dynamic_fee_threshold as f64;
+    let balanced: Vec<(&wire::Channel, f64)> = channels
+        .iter()
+        .filter(|c| {
+            c.connected
+                && c.short_channel_id.is_some()
+                && matches!(c.state, wire::ChannelState::CHANNELD_NORMAL)
+                && c.amount_msat.msat() > 0
+        })
+        .map(|c| {
+            let config = Config {
+                dynamic_fee_target_ratio: target_ratio(c, config, targets),
+                ..config.clone()
+            };
+            (c, recentre(remote_proportion(c), &config))
+        })
+        .collect();
+    let mut overfull: Vec<&(&wire::Channel, f64)> =
+        balanced.iter().filter(|(_, p)| *p < threshold).collect();
+    let mut depleted: Vec<&(&wire::Channel, f64)> = balanced
+        .iter()
+        .filter(|(_, p)| *p > 1.0 - threshold)
+        .collect();
+    overfull.sort_by(|a, b| a.1.total_cmp(&b.1));
+    depleted.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut pairs = vec![];
+    for (source, _) in overfull {
+        // A circle back to the same peer would just be a payment to them
+        let position = depleted
+            .iter()
+            .position(|(t, _)| t.peer_id != source.peer_id);
+        if let Some(i) = position {
+            pairs.push(((*source).clone(), depleted.remove(i).0.clone()));
+        }
+    }
+    pairs
+}
+
+/// As much as `dynamic_rebalance_amount`, but no more than brings either
+/// channel to its target.
+pub fn rebalance_amount(
+    source: &wire::Channel,
+    target: &wire::Channel,
+    config: &Config,
+    targets: &HashMap<String, f64>,
+) -> u64 {
+    let share =
+        |c: &wire::Channel| (c.amount_msat.msat() as f64 * target_ratio(c, config, targets)) as u64;
+    let spare = source.our_amount_msat.msat().saturating_sub(share(source));
+    let wanted = share(target).saturating_sub(target.our_amount_msat.msat());
+    (config.dynamic_rebalance_amount.max(0) as u64)
+        .min(spare)
+        .min(wanted)
+}
+
+/// Which way a hop crosses a channel: 0 from the node with the lower id.
+fn direction(from: &str, to: &str) -> u32 {
+    if from < to {
+        0
+    } else {
+        1
+    }
+}
+
+/// Works amounts and delays back from the last hop, adding what each
+/// forwarding node charges for the channel it sends on.
+pub fn set_route_fees(
+    route: &mut [wire::RouteHop],
+    amount_msat: u64,
+    policies: &[wire::ListChannel],
+) -> Result<(), Error> {
+    let mut amount = amount_msat;
+    let mut delay = FINAL_CLTV;
+    for i in (0..route.len()).rev() {
+        route[i].amount_msat = Amount::from_msat(amount);
+        route[i].delay = delay;
+        if i == 0 {
+            // The first hop leaves us, and we don't charge ourselves
+            break;
+        }
+        let forwarder = &route[i - 1].id;
+        let policy = policies
+            .iter()
+            .find(|c| c.short_channel_id == route[i].channel && &c.source == forwarder)
+            .ok_or_else(|| anyhow!("No policy for {} from {}", route[i].channel, forwarder))?;
+        amount += policy.base_fee_millisatoshi
+            + (amount as u128 * policy.fee_per_millionth as u128 / 1_000_000) as u64;
+        delay += policy.delay;
+    }
+    Ok(())
+}
+
+/// Moves liquidity out of `source` and back in through `target` by paying
+/// ourselves, as long as it costs less than `dynamic_rebalance_budget` of what
+/// `target` will charge to send it on again.
+async fn rebalance_pair<B: LightningBackend>(
+    source: &wire::Channel,
+    target: &wire::Channel,
+    config: &Config,
+    targets: &HashMap<String, f64>,
+    state: &PluginState<B>,
+) -> RebalanceOutcome {
+    let source_id = source.short_channel_id.clone().unwrap_or_default();
+    let target_id = target.short_channel_id.clone().unwrap_or_default();
+    let amount_msat = rebalance_amount(source, target, config, targets);
+    let mut outcome = RebalanceOutcome {
+        source: source_id.clone(),
+        target: target_id.clone(),
+        amount_msat,
+        fee_msat: None,
+        max_fee_msat: 0,
+        status: RebalanceStatus::Skipped,
+        reason: None,
+    };
+    if amount_msat == 0 {
+        outcome.reason = Some("nothing to move".to_string());
+        return outcome;
+    }
+    let attempt = async {
+        let target_fee = calculate_fee_target(target, config).await?;
+        outcome.max_fee_msat = (amount_msat as f64 * target_fee as f64 / 1_000_000.0
+            * config.dynamic_rebalance_budget) as u64;
+
+        let node_id = state.backend.get_node_id().await?;
+        // The middle mustn't come back through us, or through either end of
+        // the circle
+        let mut exclude: Vec<String> = [&source_id, &target_id]
+            .iter()
+            .flat_map(|id| [format!("{}/0", id), format!("{}/1", id)])
+            .collect();
+        exclude.push(node_id.clone());
+        let middle = state
+            .backend
+            .get_route(&source.peer_id, &target.peer_id, amount_msat, &exclude)
+            .await?;
+        let mut route = vec![wire::RouteHop {
+            id: source.peer_id.clone(),
+            channel: source_id.clone(),
+            direction: direction(&node_id, &source.peer_id),
+            amount_msat: Amount::from_msat(0),
+            delay: 0,
+        }];
+        route.extend(middle);
+        route.push(wire::RouteHop {
+            id: node_id.clone(),
+            channel: target_id.clone(),
+            direction: direction(&target.peer_id, &node_id),
+            amount_msat: Amount::from_msat(0),
+            delay: 0,
+        });
+        let mut policies = vec![];
+        for hop in &route[1..] {
+            policies.extend(state.backend.get_channel(&hop.channel).await?);
+        }
+        set_route_fees(&mut route, amount_msat, &policies)?;
+        let fee_msat = route[0].amount_msat.msat() - amount_msat;
+        outcome.fee_msat = Some(fee_msat);
+        if fee_msat > outcome.max_fee_msat {
+            outcome.reason = Some(format!(
+                "route costs {}msat, budget is {}msat",
+                fee_msat, outcome.max_fee_msat
+            ));
+            return Ok(());
+        }
+        if config.dynamic_fees_dry_run {
+            outcome.reason = Some("dry run".to_string());
+            return Ok(());
+        }
+
+        let label = format!("ceebalancer-{}-{}-{}", source_id, target_id, now());
+        // The description is what `refills` recognises the payment by
+        let description = format!("{} to {}", source_id, target_id);
+        let invoice = state
+            .backend
+            .create_invoice(amount_msat, &label, &description)
+            .await?;
+        if let Err(e) = state.backend.send_to_route(&route, &invoice).await {
+            if let Err(e) = state.backend.delete_invoice(&label).await {
+                log::warn!("Error deleting rebalance invoice {}: {:?}", label, e);
+            }
+            return Err(e);
+        }
+        outcome.status = RebalanceStatus::Succeeded;
+        Ok::<_, Error>(())
+    };
+    if let Err(e) = attempt.await {
+        outcome.status = RebalanceStatus::Failed;
+        outcome.reason = Some(e.to_string());
+    }
+    outcome
+}
+
+/// Rebalances up to `dynamic_rebalance_max_pairs` pairs of channels, one
+/// after the other, then re-evaluates the fees of the ones that moved.
+pub async fn rebalance<B: LightningBackend>(
+    config: Arc<Config>,
+    state: &PluginState<B>,
+) -> Result<Vec<RebalanceOutcome>, Error> {
+    let _running = state
+        .rebalancing
+        .try_lock()
+        .map_err(|_| anyhow!("A rebalance is already running"))?;
+    let channels: Vec<wire::Channel> = state
+        .backend
+        .list_channels()
+        .await?
+        .into_iter()
+        .filter(|c| !state.store.override_for(c).is_some_and(|o| o.exclude))
+        .collect();
+    let targets = target_ratios(&channels, &config, state).await;
+
+    let mut outcomes = vec![];
+    let mut moved = vec![];
+    for (source, target) in pick_pairs(&channels, &config, &targets)
+        .into_iter()
+        .take(config.dynamic_rebalance_max_pairs.max(0) as usize)
+    {
+        let outcome = rebalance_pair(&source, &target, &config, &targets, state).await;
+        log::info!("Rebalance: {:?}", outcome);
+        if outcome.status == RebalanceStatus::Succeeded {
+            moved.push(outcome.source.clone());
+            moved.push(outcome.target.clone());
+        }
+        outcomes.push(outcome);
+    }
+    if config.dynamic_fees && !moved.is_empty() {
+        set_channel_fees_for(config.clone(), state, &moved).await?;
+    }
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn channel(short_channel_id: &str, peer_id: &str, ours: u64) -> wire::Channel {
+        wire::Channel {
+            peer_id: peer_id.to_string(),
+            short_channel_id: Some(short_channel_id.to_string()),
+            ..crate::test_channel(1_000_000, ours)
+        }
+    }
+
+    fn ids(pairs: Vec<(wire::Channel, wire::Channel)>) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(s, t)| {
+                (
+                    s.short_channel_id.clone().unwrap(),
+                    t.short_channel_id.clone().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pairs_the_extremes_across_peers() {
+        let config = Config {
+            dynamic_fee_threshold: 0.2,
+            dynamic_rebalance_amount: 1_000_000,
+            ..Config::default()
+        };
+        let channels = vec![
+            channel("1x1x1", "02a", 900_000),
+            channel("2x2x2", "02b", 990_000),
+            channel("3x3x3", "02b", 10_000),
+            channel("4x4x4", "02c", 100_000),
+            channel("5x5x5", "02d", 500_000),
+        ];
+        let targets = HashMap::new();
+
+        assert_eq!(
+            ids(pick_pairs(&channels, &config, &targets)),
+            vec![
+                ("2x2x2".to_string(), "4x4x4".to_string()),
+                ("1x1x1".to_string(), "3x3x3".to_string()),
+            ]
+        );
+        assert_eq!(
+            rebalance_amount(&channels[1], &channels[3], &config, &targets),
+            400_000
+        );
+    }
+
+    #[test]
+    fn channels_are_balanced_to_their_own_target() {
+        let config = Config {
+            dynamic_fee_threshold: 0.2,
+            dynamic_rebalance_amount: 1_000_000,
+            ..Config::default()
+        };
+        let channels = vec![
+            channel("1x1x1", "02a", 900_000),
+            channel("2x2x2", "02b", 950_000),
+            channel("3x3x3", "02c", 200_000),
+        ];
+        // 1x1x1 sits on its target, and 3x3x3 is further below its own than
+        // the threshold
+        let targets = HashMap::from([("1x1x1".to_string(), 0.9), ("3x3x3".to_string(), 0.9)]);
+
+        assert_eq!(
+            ids(pick_pairs(&channels, &config, &targets)),
+            vec![("2x2x2".to_string(), "3x3x3".to_string())]
+        );
+        assert_eq!(
+            rebalance_amount(&channels[1], &channels[2], &config, &targets),
+            450_000
+        );
+    }
+
+    #[test]
+    fn fees_are_added_from_the_end() {
+        let hop = |id: &str, channel: &str| wire::RouteHop {
+            id: id.to_string(),
+            channel: channel.to_string(),
+            direction: 0,
+            amount_msat: Amount::from_msat(0),
+            delay: 0,
+        };
+        let policy = |channel: &str, source: &str, base, ppm| wire::ListChannel {
+            source: source.to_string(),
+            destination: String::new(),
+            short_channel_id: channel.to_string(),
+            amount_msat: Amount::from_msat(0),
+            base_fee_millisatoshi: base,
+            fee_per_millionth: ppm,
+            delay: 6,
+            htlc_minimum_msat: Amount::from_msat(0),
+            htlc_maximum_msat: None,
+            active: true,
+        };
+        let mut route = vec![
+            hop("02a", "1x1x1"),
+            hop("02b", "2x2x2"),
+            hop("02us", "3x3x3"),
+        ];
+        let policies = vec![
+            policy("2x2x2", "02a", 1000, 100),
+            policy("3x3x3", "02b", 0, 500),
+            policy("3x3x3", "02us", 9999, 9999),
+        ];
+
+        set_route_fees(&mut route, 1_000_000, &policies).unwrap();
+
+        assert_eq!(route[2].amount_msat.msat(), 1_000_000);
+        assert_eq!(route[1].amount_msat.msat(), 1_000_500);
+        assert_eq!(route[0].amount_msat.msat(), 1_001_600);
+        assert_eq!(
+            (route[0].delay, route[1].delay, route[2].delay),
+            (30, 24, 18)
+        );
+    }
+}
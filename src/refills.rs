@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+
+use anyhow::Error;
+use serde::Serialize;
+
+use crate::{now, wire, Config, LightningBackend, PluginState};
+
+/// A circular payment that moved liquidity from `source` into `target`.
+/// Refills are recognised by the `<source> to <target>` invoice description
+/// both our rebalancer and the `rebalance` plugin use.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Refill {
+    pub payment_hash: String,
+    pub source: String,
+    pub target: String,
+    pub amount_msat: u64,
+    pub fee_msat: u64,
+    pub fee_ppm: f64,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RefillReport {
+    #[serde(flatten)]
+    pub refill: Refill,
+    /// What `target` has earned per forwarded sat, in ppm.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub earned_ppm: Option<f64>,
+    /// The refill cost more than the channel has been earning, so selling
+    /// the liquidity on at the usual rate loses money.
+    pub unprofitable: bool,
+}
+
+/// Parses `<short_channel_id> to <short_channel_id>`.
+fn channels_of(description: &str) -> Option<(String, String)> {
+    let (source, target) = description.split_once(" to ")?;
+    let is_scid = |s: &str| s.split('x').count() == 3;
+    if is_scid(source) && is_scid(target) {
+        Some((source.to_string(), target.to_string()))
+    } else {
+        None
+    }
+}
+
+/// Joins our completed self-payments with the invoices they paid.
+pub fn refills(
+    sendpays: &[wire::SendPay],
+    invoices: &[wire::ListInvoice],
+    node_id: &str,
+) -> Vec<Refill> {
+    let descriptions: HashMap<&str, &str> = invoices
+        .iter()
+        .filter_map(|i| Some((i.payment_hash.as_str(), i.description.as_deref()?)))
+        .collect();
+    sendpays
+        .iter()
+        .filter(|p| p.status == "complete" && p.destination.as_deref() == Some(node_id))
+        .filter_map(|p| {
+            let (source, target) = channels_of(descriptions.get(p.payment_hash.as_str())?)?;
+            let amount_msat = p.amount_msat?.msat();
+            let fee_msat = p.amount_sent_msat.msat().saturating_sub(amount_msat);
+            Some(Refill {
+                payment_hash: p.payment_hash.clone(),
+                source,
+                target,
+                amount_msat,
+                fee_msat,
+                fee_ppm: fee_msat as f64 * 1_000_000.0 / amount_msat.max(1) as f64,
+                created_at: p.created_at,
+            })
+        })
+        .collect()
+}
+
+/// Fee earned per msat forwarded out of the channel, in ppm, over every
+/// settled forward.
+pub fn earned_ppm(forwards: &[wire::Forward], short_channel_id: &str) -> Option<f64> {
+    let (fee, out) = forwards
+        .iter()
+        .filter(|f| {
+            f.status == wire::ForwardStatus::Settled
+                && f.out_channel.as_deref() == Some(short_channel_id)
+        })
+        .fold((0u64, 0u64), |(fee, out), f| {
+            (
+                fee + f.fee_msat.map(|a| a.msat()).unwrap_or(0),
+                out + f.out_msat.map(|a| a.msat()).unwrap_or(0),
+            )
+        });
+    if out == 0 {
+        None
+    } else {
+        Some(fee as f64 * 1_000_000.0 / out as f64)
+    }
+}
+
+pub fn report(refills: Vec<Refill>, forwards: &[wire::Forward]) -> Vec<RefillReport> {
+    refills
+        .into_iter()
+        .map(|refill| {
+            let earned_ppm = earned_ppm(forwards, &refill.target);
+            RefillReport {
+                unprofitable: refill.fee_ppm > earned_ppm.unwrap_or(0.0),
+                earned_ppm,
+                refill,
+            }
+        })
+        .collect()
+}
+
+/// The cost of the latest refill of each channel within `window` seconds,
+/// rounded up to a whole ppm.
+pub fn floors(refills: &[Refill], now: u64, window: u64) -> HashMap<String, u32> {
+    let mut latest: HashMap<String, &Refill> = HashMap::new();
+    for refill in refills
+        .iter()
+        .filter(|r| r.created_at >= now.saturating_sub(window))
+    {
+        let entry = latest.entry(refill.target.clone()).or_insert(refill);
+        if refill.created_at > entry.created_at {
+            *entry = refill;
+        }
+    }
+    latest
+        .into_iter()
+        .map(|(target, refill)| (target, refill.fee_ppm.ceil() as u32))
+        .collect()
+}
+
+/// The refills made since `since`.  Only the invoices of payments to
+/// ourselves are looked up, as there are few of those compared to every
+/// invoice the node ever issued.
+pub async fn load_refills<B: LightningBackend>(
+    state: &PluginState<B>,
+    since: u64,
+) -> Result<Vec<Refill>, Error> {
+    let node_id = state.backend.get_node_id().await?;
+    let sendpays: Vec<wire::SendPay> = state
+        .backend
+        .list_sendpays()
+        .await?
+        .into_iter()
+        .filter(|p| p.created_at >= since && p.destination.as_deref() == Some(&node_id))
+        .collect();
+    let mut invoices = vec![];
+    for payment in &sendpays {
+        if let Some(invoice) = state.backend.get_invoice(&payment.payment_hash).await? {
+            invoices.push(invoice);
+        }
+    }
+    Ok(refills(&sendpays, &invoices, &node_id))
+}
+
+/// Everything `ceebalancer-refills` returns.
+pub async fn refill_report<B: LightningBackend>(
+    state: &PluginState<B>,
+) -> Result<Vec<RefillReport>, Error> {
+    let refills = load_refills(state, 0).await?;
+    let forwards = state.backend.list_forwards().await?;
+    Ok(report(refills, &forwards))
+}
+
+/// The fee floor for each recently refilled channel.  Without the payment
+/// history there's no floor rather than no fee update.
+pub async fn refill_floors<B: LightningBackend>(
+    config: &Config,
+    state: &PluginState<B>,
+) -> HashMap<String, u32> {
+    if config.dynamic_fee_refill_floor_window <= 0 {
+        return HashMap::new();
+    }
+    let now = now();
+    let window = config.dynamic_fee_refill_floor_window as u64;
+    match load_refills(state, now.saturating_sub(window)).await {
+        Ok(refills) => floors(&refills, now, window),
+        Err(e) => {
+            log::warn!("Error looking up refill costs: {:?}", e);
+            HashMap::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primitives::Amount;
+
+    fn sendpay(hash: &str, destination: &str, amount: u64, sent: u64, at: u64) -> wire::SendPay {
+        wire::SendPay {
+            payment_hash: hash.to_string(),
+            status: "complete".to_string(),
+            destination: Some(destination.to_string()),
+            amount_msat: Some(Amount::from_msat(amount)),
+            amount_sent_msat: Amount::from_msat(sent),
+            created_at: at,
+        }
+    }
+
+    fn invoice(hash: &str, description: &str) -> wire::ListInvoice {
+        wire::ListInvoice {
+            label: hash.to_string(),
+            payment_hash: hash.to_string(),
+            status: "paid".to_string(),
+            description: Some(description.to_string()),
+        }
+    }
+
+    #[test]
+    fn refills_are_costed_and_flagged() {
+        let sendpays = vec![
+            sendpay("aa", "02us", 1_000_000, 1_000_500, 100),
+            sendpay("bb", "02us", 1_000_000, 1_000_200, 200),
+            sendpay("cc", "03them", 1_000_000, 1_001_000, 300),
+            sendpay("dd", "02us", 1_000_000, 1_000_900, 400),
+        ];
+        let invoices = vec![
+            invoice("aa", "1x1x1 to 2x2x2"),
+            invoice("bb", "1x1x1 to 2x2x2"),
+            invoice("cc", "1x1x1 to 2x2x2"),
+            invoice("dd", "coffee"),
+        ];
+        let refills = refills(&sendpays, &invoices, "02us");
+        assert_eq!(refills.len(), 2);
+        assert_eq!(refills[0].fee_msat, 500);
+        assert_eq!(refills[0].fee_ppm, 500.0);
+
+        assert_eq!(floors(&refills, 250, 1000)["2x2x2"], 200);
+        assert!(floors(&refills, 5000, 1000).is_empty());
+
+        let forwards = vec![wire::Forward {
+            in_channel: "3x3x3".to_string(),
+            out_channel: Some("2x2x2".to_string()),
+            in_msat: Some(Amount::from_msat(10_003_000)),
+            out_msat: Some(Amount::from_msat(10_000_000)),
+            fee_msat: Some(Amount::from_msat(3_000)),
+            status: wire::ForwardStatus::Settled,
+            received_time: 0.0,
+            resolved_time: None,
+        }];
+        let report = report(refills, &forwards);
+        assert_eq!(report[0].earned_ppm, Some(300.0));
+        assert!(report[0].unprofitable);
+        assert!(!report[1].unprofitable);
+    }
+}
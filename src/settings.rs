@@ -0,0 +1,841 @@
+use cln_plugin::options;
+
+use crate::analytics;
+use crate::classify;
+use crate::htlc_max;
+use crate::pid;
+use crate::validation::{ConfigError, ConfigErrors};
+use crate::{Config, FeeCurve};
+
+/// Builds a `Config` from option values looked up by name, as they'd come
+/// from lightningd.  Missing options get their default.  Every bad value is
+/// reported, not just the first.
+pub fn parse_configuration<F>(option: F) -> Result<Config, ConfigErrors>
+where
+    F: Fn(&str) -> Option<options::Value>,
+{
+    let c = Config::default();
+    let mut errors = vec![];
+
+    let dynamic_fees = match option("dynamic-fees") {
+        Some(options::Value::Boolean(false)) => {
+            log::debug!("`dynamic-fees` option is set to false.  Disabling");
+            false
+        }
+        Some(options::Value::Boolean(true)) => {
+            log::debug!("`dynamic-fees` option is set to true.  Enabling.");
+            true
+        }
+        None => {
+            log::info!("Missing 'dynamic-fees' option.  Disabling.");
+            false
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fees",
+                reason: format!("not a valid boolean: {:?}", o),
+            });
+            c.dynamic_fees
+        }
+    };
+    let dynamic_fees_dry_run = match option("dynamic-fees-dry-run") {
+        Some(options::Value::Boolean(b)) => b,
+        None => {
+            log::info!("Missing 'dynamic-fees-dry-run' option.  Using default.");
+            c.dynamic_fees_dry_run
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fees-dry-run",
+                reason: format!("not a valid boolean: {:?}", o),
+            });
+            c.dynamic_fees_dry_run
+        }
+    };
+    let dynamic_fee_min = match option("dynamic-fee-min") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-min' option.  Using default.");
+            c.dynamic_fee_min
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-min",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_min
+        }
+    };
+    let dynamic_fee_max = match option("dynamic-fee-max") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-max' option.  Using default.");
+            c.dynamic_fee_max
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-max",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_max
+        }
+    };
+    let dynamic_fee_threshold = match option("dynamic-fee-threshold") {
+        Some(options::Value::Integer(i)) => i as f32 / 100.0,
+        None => {
+            log::info!("Missing 'dynamic-fee-threshold' option.  Using default.");
+            c.dynamic_fee_threshold
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-threshold",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_threshold
+        }
+    };
+    let dynamic_fee_width = match option("dynamic-fee-width") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-width' option.  Using default.");
+            c.dynamic_fee_width
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-width",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_width
+        }
+    };
+    let dynamic_fee_update_interval = match option("dynamic-fee-update-interval") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-update-interval' option.  Using default.");
+            c.dynamic_fee_update_interval
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-update-interval",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_update_interval
+        }
+    };
+
+    let dynamic_fee_curve = match option("dynamic-fee-curve") {
+        Some(options::Value::String(s)) => match s.parse::<FeeCurve>() {
+            Ok(v) => v,
+            Err(e) => {
+                errors.push(ConfigError::Unparsable {
+                    option: "dynamic-fee-curve",
+                    reason: e.to_string(),
+                });
+                c.dynamic_fee_curve
+            }
+        },
+        None => {
+            log::info!("Missing 'dynamic-fee-curve' option.  Using default.");
+            c.dynamic_fee_curve
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-curve",
+                reason: format!("not a valid string: {:?}", o),
+            });
+            c.dynamic_fee_curve
+        }
+    };
+
+    let dynamic_fee_target_ratio = match option("dynamic-fee-target-ratio") {
+        Some(options::Value::Integer(i)) => i as f64 / 100.0,
+        None => {
+            log::info!("Missing 'dynamic-fee-target-ratio' option.  Using default.");
+            c.dynamic_fee_target_ratio
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-target-ratio",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_target_ratio
+        }
+    };
+    let dynamic_fee_target_learn_window = match option("dynamic-fee-target-learn-window") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-target-learn-window' option.  Using default.");
+            c.dynamic_fee_target_learn_window
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-target-learn-window",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_target_learn_window
+        }
+    };
+    let dynamic_fee_classify_window = match option("dynamic-fee-classify-window") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-classify-window' option.  Using default.");
+            c.dynamic_fee_classify_window
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-classify-window",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_classify_window
+        }
+    };
+    let dynamic_fee_class_curves = match option("dynamic-fee-class-curves") {
+        Some(options::Value::String(s)) => match classify::parse_class_curves(&s) {
+            Ok(v) => v,
+            Err(e) => {
+                errors.push(ConfigError::Unparsable {
+                    option: "dynamic-fee-class-curves",
+                    reason: e,
+                });
+                c.dynamic_fee_class_curves
+            }
+        },
+        None => {
+            log::info!("Missing 'dynamic-fee-class-curves' option.  Using default.");
+            c.dynamic_fee_class_curves
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-class-curves",
+                reason: format!("not a valid string: {:?}", o),
+            });
+            c.dynamic_fee_class_curves
+        }
+    };
+    let dynamic_fee_mode = match option("dynamic-fee-mode") {
+        Some(options::Value::String(s)) => match s.parse::<pid::FeeMode>() {
+            Ok(v) => v,
+            Err(e) => {
+                errors.push(ConfigError::Unparsable {
+                    option: "dynamic-fee-mode",
+                    reason: e.to_string(),
+                });
+                c.dynamic_fee_mode
+            }
+        },
+        None => {
+            log::info!("Missing 'dynamic-fee-mode' option.  Using default.");
+            c.dynamic_fee_mode
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-mode",
+                reason: format!("not a valid string: {:?}", o),
+            });
+            c.dynamic_fee_mode
+        }
+    };
+    let dynamic_fee_pid_kp = match option("dynamic-fee-pid-kp") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-pid-kp' option.  Using default.");
+            c.dynamic_fee_pid_kp
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-pid-kp",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_pid_kp
+        }
+    };
+    let dynamic_fee_pid_ki = match option("dynamic-fee-pid-ki") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-pid-ki' option.  Using default.");
+            c.dynamic_fee_pid_ki
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-pid-ki",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_pid_ki
+        }
+    };
+    let dynamic_fee_pid_kd = match option("dynamic-fee-pid-kd") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-pid-kd' option.  Using default.");
+            c.dynamic_fee_pid_kd
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-pid-kd",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_pid_kd
+        }
+    };
+    let dynamic_fee_forward_debounce = match option("dynamic-fee-forward-debounce") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-forward-debounce' option.  Using default.");
+            c.dynamic_fee_forward_debounce
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-forward-debounce",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_forward_debounce
+        }
+    };
+
+    let dynamic_fee_deadband = match option("dynamic-fee-deadband") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-deadband' option.  Using default.");
+            c.dynamic_fee_deadband
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-deadband",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_deadband
+        }
+    };
+    let dynamic_fee_htlc_max_deadband = match option("dynamic-fee-htlc-max-deadband") {
+        Some(options::Value::Integer(i)) => i as f32 / 100.0,
+        None => {
+            log::info!("Missing 'dynamic-fee-htlc-max-deadband' option.  Using default.");
+            c.dynamic_fee_htlc_max_deadband
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-htlc-max-deadband",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_htlc_max_deadband
+        }
+    };
+    let dynamic_fee_hysteresis = match option("dynamic-fee-hysteresis") {
+        Some(options::Value::Integer(i)) => i as f32 / 100.0,
+        None => {
+            log::info!("Missing 'dynamic-fee-hysteresis' option.  Using default.");
+            c.dynamic_fee_hysteresis
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-hysteresis",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_hysteresis
+        }
+    };
+
+    let dynamic_base_fee = match option("dynamic-base-fee") {
+        Some(options::Value::Boolean(b)) => b,
+        None => {
+            log::info!("Missing 'dynamic-base-fee' option.  Using default.");
+            c.dynamic_base_fee
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-base-fee",
+                reason: format!("not a valid boolean: {:?}", o),
+            });
+            c.dynamic_base_fee
+        }
+    };
+    let dynamic_base_fee_min = match option("dynamic-base-fee-min") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-base-fee-min' option.  Using default.");
+            c.dynamic_base_fee_min
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-base-fee-min",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_base_fee_min
+        }
+    };
+    let dynamic_base_fee_max = match option("dynamic-base-fee-max") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-base-fee-max' option.  Using default.");
+            c.dynamic_base_fee_max
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-base-fee-max",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_base_fee_max
+        }
+    };
+
+    let dynamic_htlc_min = match option("dynamic-htlc-min") {
+        Some(options::Value::Boolean(b)) => b,
+        None => {
+            log::info!("Missing 'dynamic-htlc-min' option.  Using default.");
+            c.dynamic_htlc_min
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-htlc-min",
+                reason: format!("not a valid boolean: {:?}", o),
+            });
+            c.dynamic_htlc_min
+        }
+    };
+    let dynamic_htlc_min_normal = match option("dynamic-htlc-min-normal") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-htlc-min-normal' option.  Using default.");
+            c.dynamic_htlc_min_normal
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-htlc-min-normal",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_htlc_min_normal
+        }
+    };
+    let dynamic_htlc_min_raised = match option("dynamic-htlc-min-raised") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-htlc-min-raised' option.  Using default.");
+            c.dynamic_htlc_min_raised
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-htlc-min-raised",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_htlc_min_raised
+        }
+    };
+    let dynamic_htlc_min_depleted = match option("dynamic-htlc-min-depleted") {
+        Some(options::Value::Integer(i)) => i as f32 / 100.0,
+        None => {
+            log::info!("Missing 'dynamic-htlc-min-depleted' option.  Using default.");
+            c.dynamic_htlc_min_depleted
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-htlc-min-depleted",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_htlc_min_depleted
+        }
+    };
+    let dynamic_htlc_min_dust = match option("dynamic-htlc-min-dust") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-htlc-min-dust' option.  Using default.");
+            c.dynamic_htlc_min_dust
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-htlc-min-dust",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_htlc_min_dust
+        }
+    };
+    let dynamic_htlc_min_dust_limit = match option("dynamic-htlc-min-dust-limit") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-htlc-min-dust-limit' option.  Using default.");
+            c.dynamic_htlc_min_dust_limit
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-htlc-min-dust-limit",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_htlc_min_dust_limit
+        }
+    };
+
+    let dynamic_htlc_max_ladder = match option("dynamic-htlc-max-ladder") {
+        Some(options::Value::String(s)) => match htlc_max::parse_ladder(&s) {
+            Ok(v) => v,
+            Err(e) => {
+                errors.push(ConfigError::Unparsable {
+                    option: "dynamic-htlc-max-ladder",
+                    reason: e.to_string(),
+                });
+                c.dynamic_htlc_max_ladder
+            }
+        },
+        None => {
+            log::info!("Missing 'dynamic-htlc-max-ladder' option.  Using default.");
+            c.dynamic_htlc_max_ladder
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-htlc-max-ladder",
+                reason: format!("not a valid string: {:?}", o),
+            });
+            c.dynamic_htlc_max_ladder
+        }
+    };
+    let dynamic_htlc_max_ratio = match option("dynamic-htlc-max-ratio") {
+        Some(options::Value::Integer(i)) => i as f64 / 100.0,
+        None => {
+            log::info!("Missing 'dynamic-htlc-max-ratio' option.  Using default.");
+            c.dynamic_htlc_max_ratio
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-htlc-max-ratio",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_htlc_max_ratio
+        }
+    };
+    let dynamic_htlc_max_rounding = match option("dynamic-htlc-max-rounding") {
+        Some(options::Value::String(s)) => match s.parse::<htlc_max::Rounding>() {
+            Ok(v) => v,
+            Err(e) => {
+                errors.push(ConfigError::Unparsable {
+                    option: "dynamic-htlc-max-rounding",
+                    reason: e.to_string(),
+                });
+                c.dynamic_htlc_max_rounding
+            }
+        },
+        None => {
+            log::info!("Missing 'dynamic-htlc-max-rounding' option.  Using default.");
+            c.dynamic_htlc_max_rounding
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-htlc-max-rounding",
+                reason: format!("not a valid string: {:?}", o),
+            });
+            c.dynamic_htlc_max_rounding
+        }
+    };
+    let dynamic_htlc_max_source = match option("dynamic-htlc-max-source") {
+        Some(options::Value::String(s)) => match s.parse::<htlc_max::Source>() {
+            Ok(v) => v,
+            Err(e) => {
+                errors.push(ConfigError::Unparsable {
+                    option: "dynamic-htlc-max-source",
+                    reason: e.to_string(),
+                });
+                c.dynamic_htlc_max_source
+            }
+        },
+        None => {
+            log::info!("Missing 'dynamic-htlc-max-source' option.  Using default.");
+            c.dynamic_htlc_max_source
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-htlc-max-source",
+                reason: format!("not a valid string: {:?}", o),
+            });
+            c.dynamic_htlc_max_source
+        }
+    };
+    let dynamic_htlc_max_reserve = match option("dynamic-htlc-max-reserve") {
+        Some(options::Value::Integer(i)) => i as f64 / 100.0,
+        None => {
+            log::info!("Missing 'dynamic-htlc-max-reserve' option.  Using default.");
+            c.dynamic_htlc_max_reserve
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-htlc-max-reserve",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_htlc_max_reserve
+        }
+    };
+
+    let dynamic_fee_privacy = match option("dynamic-fee-privacy") {
+        Some(options::Value::Boolean(b)) => b,
+        None => {
+            log::info!("Missing 'dynamic-fee-privacy' option.  Using default.");
+            c.dynamic_fee_privacy
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-privacy",
+                reason: format!("not a valid boolean: {:?}", o),
+            });
+            c.dynamic_fee_privacy
+        }
+    };
+    let dynamic_fee_privacy_jitter = match option("dynamic-fee-privacy-jitter") {
+        Some(options::Value::Integer(i)) => i as f64 / 100.0,
+        None => {
+            log::info!("Missing 'dynamic-fee-privacy-jitter' option.  Using default.");
+            c.dynamic_fee_privacy_jitter
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-privacy-jitter",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_privacy_jitter
+        }
+    };
+    let dynamic_fee_privacy_htlc_max_step = match option("dynamic-fee-privacy-htlc-max-step") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-privacy-htlc-max-step' option.  Using default.");
+            c.dynamic_fee_privacy_htlc_max_step
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-privacy-htlc-max-step",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_privacy_htlc_max_step
+        }
+    };
+    let dynamic_fee_privacy_delay = match option("dynamic-fee-privacy-delay") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-privacy-delay' option.  Using default.");
+            c.dynamic_fee_privacy_delay
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-privacy-delay",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_privacy_delay
+        }
+    };
+
+    let dynamic_fee_competitors = match option("dynamic-fee-competitors") {
+        Some(options::Value::Boolean(b)) => b,
+        None => {
+            log::info!("Missing 'dynamic-fee-competitors' option.  Using default.");
+            c.dynamic_fee_competitors
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-competitors",
+                reason: format!("not a valid boolean: {:?}", o),
+            });
+            c.dynamic_fee_competitors
+        }
+    };
+    let dynamic_fee_competitor_percentile = match option("dynamic-fee-competitor-percentile") {
+        Some(options::Value::Integer(i)) => i as f64 / 100.0,
+        None => {
+            log::info!("Missing 'dynamic-fee-competitor-percentile' option.  Using default.");
+            c.dynamic_fee_competitor_percentile
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-competitor-percentile",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_competitor_percentile
+        }
+    };
+    let dynamic_fee_competitor_min_count = match option("dynamic-fee-competitor-min-count") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-competitor-min-count' option.  Using default.");
+            c.dynamic_fee_competitor_min_count
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-competitor-min-count",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_competitor_min_count
+        }
+    };
+
+    let dynamic_fee_report_windows = match option("dynamic-fee-report-windows") {
+        Some(options::Value::String(s)) => match analytics::parse_windows(&s) {
+            Ok(v) => v,
+            Err(e) => {
+                errors.push(ConfigError::Unparsable {
+                    option: "dynamic-fee-report-windows",
+                    reason: e,
+                });
+                c.dynamic_fee_report_windows
+            }
+        },
+        None => {
+            log::info!("Missing 'dynamic-fee-report-windows' option.  Using default.");
+            c.dynamic_fee_report_windows
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-report-windows",
+                reason: format!("not a valid string: {:?}", o),
+            });
+            c.dynamic_fee_report_windows
+        }
+    };
+
+    let dynamic_fee_refill_floor_window = match option("dynamic-fee-refill-floor-window") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-fee-refill-floor-window' option.  Using default.");
+            c.dynamic_fee_refill_floor_window
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-fee-refill-floor-window",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_fee_refill_floor_window
+        }
+    };
+    let dynamic_rebalance = match option("dynamic-rebalance") {
+        Some(options::Value::Boolean(b)) => b,
+        None => {
+            log::info!("Missing 'dynamic-rebalance' option.  Using default.");
+            c.dynamic_rebalance
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-rebalance",
+                reason: format!("not a valid boolean: {:?}", o),
+            });
+            c.dynamic_rebalance
+        }
+    };
+    let dynamic_rebalance_interval = match option("dynamic-rebalance-interval") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-rebalance-interval' option.  Using default.");
+            c.dynamic_rebalance_interval
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-rebalance-interval",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_rebalance_interval
+        }
+    };
+    let dynamic_rebalance_amount = match option("dynamic-rebalance-amount") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-rebalance-amount' option.  Using default.");
+            c.dynamic_rebalance_amount
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-rebalance-amount",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_rebalance_amount
+        }
+    };
+    let dynamic_rebalance_budget = match option("dynamic-rebalance-budget") {
+        Some(options::Value::Integer(i)) => i as f64 / 100.0,
+        None => {
+            log::info!("Missing 'dynamic-rebalance-budget' option.  Using default.");
+            c.dynamic_rebalance_budget
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-rebalance-budget",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_rebalance_budget
+        }
+    };
+    let dynamic_rebalance_max_pairs = match option("dynamic-rebalance-max-pairs") {
+        Some(options::Value::Integer(i)) => i,
+        None => {
+            log::info!("Missing 'dynamic-rebalance-max-pairs' option.  Using default.");
+            c.dynamic_rebalance_max_pairs
+        }
+        Some(o) => {
+            errors.push(ConfigError::Unparsable {
+                option: "dynamic-rebalance-max-pairs",
+                reason: format!("not a valid integer: {:?}", o),
+            });
+            c.dynamic_rebalance_max_pairs
+        }
+    };
+
+    let config = Config {
+        dynamic_fees,
+        dynamic_fees_dry_run,
+        dynamic_fee_min,
+        dynamic_fee_max,
+        dynamic_fee_threshold,
+        dynamic_fee_width,
+        dynamic_fee_update_interval,
+        dynamic_fee_curve,
+        dynamic_fee_target_ratio,
+        dynamic_fee_target_learn_window,
+        dynamic_fee_classify_window,
+        dynamic_fee_class_curves,
+        dynamic_fee_mode,
+        dynamic_fee_pid_kp,
+        dynamic_fee_pid_ki,
+        dynamic_fee_pid_kd,
+        dynamic_fee_forward_debounce,
+        dynamic_fee_deadband,
+        dynamic_fee_htlc_max_deadband,
+        dynamic_fee_hysteresis,
+        dynamic_base_fee,
+        dynamic_base_fee_min,
+        dynamic_base_fee_max,
+        dynamic_htlc_min,
+        dynamic_htlc_min_normal,
+        dynamic_htlc_min_raised,
+        dynamic_htlc_min_depleted,
+        dynamic_htlc_min_dust,
+        dynamic_htlc_min_dust_limit,
+        dynamic_htlc_max_ladder,
+        dynamic_htlc_max_ratio,
+        dynamic_htlc_max_rounding,
+        dynamic_htlc_max_source,
+        dynamic_htlc_max_reserve,
+        dynamic_fee_privacy,
+        dynamic_fee_privacy_jitter,
+        dynamic_fee_privacy_htlc_max_step,
+        dynamic_fee_privacy_delay,
+        dynamic_fee_competitors,
+        dynamic_fee_competitor_percentile,
+        dynamic_fee_competitor_min_count,
+        dynamic_fee_report_windows,
+        dynamic_fee_refill_floor_window,
+        dynamic_rebalance,
+        dynamic_rebalance_interval,
+        dynamic_rebalance_amount,
+        dynamic_rebalance_budget,
+        dynamic_rebalance_max_pairs,
+    };
+    if let Err(ConfigErrors(invalid)) = config.validate() {
+        errors.extend(invalid);
+    }
+    if errors.is_empty() {
+        Ok(config)
+    } else {
+        Err(ConfigErrors(errors))
+    }
+}
+
+/// Option value for a setting read from JSON, e.g. a daemon config file.
+pub fn option_value(value: &serde_json::Value) -> Option<options::Value> {
+    match value {
+        serde_json::Value::Bool(b) => Some(options::Value::Boolean(*b)),
+        serde_json::Value::Number(n) => n.as_i64().map(options::Value::Integer),
+        serde_json::Value::String(s) => Some(options::Value::String(s.clone())),
+        _ => None,
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::io::BufRead;
+
+use anyhow::Error;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+
+use crate::pid::{self, FeeMode, PidState};
+use crate::primitives::Amount;
+use crate::store::{Record, Snapshot};
+use crate::{
+    apply_hysteresis, calculate_base_fee, calculate_fee_target, calculate_htlc_max, wire,
+    within_deadband, ChannelPolicy, Config,
+};
+
+/// A channel's balance over time, oldest first.
+pub type History = BTreeMap<String, Vec<Snapshot>>;
+
+/// Reads the snapshots out of a store file, ignoring everything else in it.
+pub fn read_history<R: BufRead>(reader: R) -> Result<History, Error> {
+    let mut history = History::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Ok(Record::Snapshot {
+            short_channel_id,
+            snapshot,
+        }) = serde_json::from_str(&line)
+        {
+            history.entry(short_channel_id).or_default().push(snapshot);
+        }
+    }
+    for snapshots in history.values_mut() {
+        snapshots.sort_by_key(|s| s.timestamp);
+    }
+    Ok(history)
+}
+
+/// A made up channel whose balance random walks, with a forward out of it
+/// every time the local balance drops.  Seeded, so runs are comparable.
+pub fn synthetic(steps: usize, interval: u64, seed: u64) -> (History, Vec<wire::Forward>) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let amount_msat = 10_000_000_000;
+    let mut ours: i64 = amount_msat as i64 / 2;
+    let mut snapshots = vec![];
+    let mut forwards = vec![];
+    for step in 0..steps {
+        let timestamp = step as u64 * interval;
+        let delta = rng.gen_range(-500_000_000..=500_000_000);
+        let next = (ours + delta).clamp(0, amount_msat as i64);
+        if next < ours {
+            let out_msat = (ours - next) as u64;
+            forwards.push(wire::Forward {
+                in_channel: "0x0x0".to_string(),
+                out_channel: Some("1x1x1".to_string()),
+                in_msat: Some(Amount::from_msat(out_msat)),
+                out_msat: Some(Amount::from_msat(out_msat)),
+                fee_msat: None,
+                status: wire::ForwardStatus::Settled,
+                received_time: timestamp as f64,
+                resolved_time: Some(timestamp as f64),
+            });
+        }
+        ours = next;
+        snapshots.push(Snapshot {
+            timestamp,
+            our_amount_msat: ours as u64,
+            amount_msat,
+        });
+    }
+    let mut history = History::new();
+    history.insert("1x1x1".to_string(), snapshots);
+    (history, forwards)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TimelinePoint {
+    pub timestamp: u64,
+    pub fee_ppm: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base_fee_msat: Option<u64>,
+    pub htlc_max_msat: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChannelResult {
+    /// Number of `channel_update`s the policy would have sent.
+    pub updates: usize,
+    pub revenue_msat: u64,
+    /// Forwards that would have been refused for exceeding htlc_max.
+    pub rejected_forwards: usize,
+    pub timeline: Vec<TimelinePoint>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SimulationResult {
+    pub name: String,
+    pub updates: usize,
+    pub revenue_msat: u64,
+    pub rejected_forwards: usize,
+    pub channels: BTreeMap<String, ChannelResult>,
+}
+
+/// Replays the balances through the fee and htlc_max calculation as each
+/// scheduled run would have seen them, and charges every outgoing forward the
+/// policy that was in effect when it happened.
+pub async fn simulate(
+    name: &str,
+    config: &Config,
+    history: &History,
+    forwards: &[wire::Forward],
+) -> Result<SimulationResult, Error> {
+    let mut result = SimulationResult {
+        name: name.to_string(),
+        updates: 0,
+        revenue_msat: 0,
+        rejected_forwards: 0,
+        channels: BTreeMap::new(),
+    };
+    for (short_channel_id, snapshots) in history {
+        let channel = simulate_channel(short_channel_id, config, snapshots, forwards).await?;
+        result.updates += channel.updates;
+        result.revenue_msat += channel.revenue_msat;
+        result.rejected_forwards += channel.rejected_forwards;
+        result.channels.insert(short_channel_id.clone(), channel);
+    }
+    Ok(result)
+}
+
+async fn simulate_channel(
+    short_channel_id: &str,
+    config: &Config,
+    snapshots: &[Snapshot],
+    forwards: &[wire::Forward],
+) -> Result<ChannelResult, Error> {
+    let mut result = ChannelResult::default();
+    let mut applied: Option<ChannelPolicy> = None;
+    let mut last_run = None;
+    let mut controller: Option<PidState> = None;
+    for snapshot in snapshots {
+        // Snapshots can be closer together than runs, e.g. after forwards
+        if let Some(last) = last_run {
+            if snapshot.timestamp < last + config.dynamic_fee_update_interval.max(0) as u64 {
+                continue;
+            }
+        }
+        last_run = Some(snapshot.timestamp);
+        let channel = wire::Channel {
+            peer_id: String::new(),
+            connected: true,
+            state: wire::ChannelState::CHANNELD_NORMAL,
+            our_amount_msat: Amount::from_msat(snapshot.our_amount_msat),
+            amount_msat: Amount::from_msat(snapshot.amount_msat),
+            funding_txid: String::new(),
+            funding_output: 0,
+            short_channel_id: Some(short_channel_id.to_string()),
+            spendable_msat: None,
+        };
+        let fee_target = calculate_fee_target(&channel, config).await?;
+        let last_fee = applied.as_ref().map(|p| p.fee_ppm);
+        let mut pid_state = None;
+        let fee_ppm = match config.dynamic_fee_mode {
+            FeeMode::Curve => apply_hysteresis(&channel, config, last_fee, fee_target),
+            FeeMode::Pid => {
+                let start_fee = last_fee.unwrap_or(fee_target);
+                let (fee, state) = pid::pid_fee(
+                    &channel,
+                    config,
+                    controller.as_ref(),
+                    start_fee,
+                    snapshot.timestamp,
+                );
+                pid_state = Some(state);
+                fee
+            }
+        };
+        let policy = ChannelPolicy {
+            fee_ppm,
+            base_fee_msat: calculate_base_fee(&channel, config),
+            htlc_min_msat: None,
+            htlc_max_msat: calculate_htlc_max(&channel, config).await?,
+        };
+        if let Some(last) = &applied {
+            if within_deadband(last, &policy, config) {
+                continue;
+            }
+        }
+        if let Some(state) = pid_state {
+            controller = Some(pid::back_calculate(
+                &state,
+                controller.as_ref(),
+                policy.fee_ppm,
+                config,
+            ));
+        }
+        result.updates += 1;
+        result.timeline.push(TimelinePoint {
+            timestamp: snapshot.timestamp,
+            fee_ppm: policy.fee_ppm,
+            base_fee_msat: policy.base_fee_msat,
+            htlc_max_msat: policy.htlc_max_msat,
+        });
+        applied = Some(policy);
+    }
+
+    for forward in forwards {
+        if forward.status != wire::ForwardStatus::Settled
+            || forward.out_channel.as_deref() != Some(short_channel_id)
+        {
+            continue;
+        }
+        let out_msat = match forward.out_msat {
+            Some(amount) => amount.msat(),
+            None => continue,
+        };
+        let time = forward.resolved_time.unwrap_or(forward.received_time) as u64;
+        let point = match result.timeline.iter().rev().find(|p| p.timestamp <= time) {
+            Some(point) => point,
+            None => continue,
+        };
+        if out_msat > point.htlc_max_msat {
+            result.rejected_forwards += 1;
+            continue;
+        }
+        result.revenue_msat += point.base_fee_msat.unwrap_or(0)
+            + (out_msat as u128 * point.fee_ppm as u128 / 1_000_000) as u64;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn wider_steps_send_fewer_updates() {
+        let (history, forwards) = synthetic(500, 3600, 7);
+        let config = Config {
+            dynamic_fee_update_interval: 3600,
+            dynamic_fee_width: 10,
+            ..Config::default()
+        };
+        let fine = simulate("fine", &config, &history, &forwards)
+            .await
+            .unwrap();
+        let coarse = Config {
+            dynamic_fee_width: 250,
+            ..config
+        };
+        let coarse = simulate("coarse", &coarse, &history, &forwards)
+            .await
+            .unwrap();
+
+        assert!(fine.updates > coarse.updates);
+        assert!(fine.revenue_msat > 0);
+        assert_eq!(fine.channels["1x1x1"].timeline.len(), fine.updates);
+    }
+
+    #[test]
+    fn history_is_read_from_the_store_file() {
+        let file = r#"{"type":"snapshot","short_channel_id":"1x1x1","timestamp":20,"our_amount_msat":5,"amount_msat":10}
+{"type":"applied","short_channel_id":"1x1x1","timestamp":20,"fee_ppm":1,"htlc_max_msat":1}
+{"type":"snapshot","short_channel_id":"1x1x1","timestamp":10,"our_amount_msat":7,"amount_msat":10}
+"#;
+        let history = read_history(file.as_bytes()).unwrap();
+
+        assert_eq!(history["1x1x1"].len(), 2);
+        assert_eq!(history["1x1x1"][0].our_amount_msat, 7);
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::store::Store;
+use crate::{now, Config};
+
+/// Outcome of the last attempt to update a channel.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChannelOutcome {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error_time: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    last_run: Option<u64>,
+    last_run_duration: Option<Duration>,
+    next_run: Option<u64>,
+    successes: u64,
+    failures: u64,
+    channels: HashMap<String, ChannelOutcome>,
+}
+
+/// Timings of the adjustment runs and how the channel updates went.  Lives in
+/// memory only; the applied policies themselves are in the `Store`.
+#[derive(Clone, Debug, Default)]
+pub struct RunStatus {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl RunStatus {
+    pub fn new() -> RunStatus {
+        RunStatus::default()
+    }
+
+    pub fn record_run(&self, started: u64, duration: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_run = Some(started);
+        inner.last_run_duration = Some(duration);
+    }
+
+    pub fn schedule_next(&self, at: u64) {
+        self.inner.lock().unwrap().next_run = Some(at);
+    }
+
+    pub fn record_success(&self, short_channel_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.successes += 1;
+        let outcome = inner
+            .channels
+            .entry(short_channel_id.to_string())
+            .or_default();
+        outcome.last_success = Some(now());
+    }
+
+    pub fn record_failure(&self, short_channel_id: &str, error: &anyhow::Error) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures += 1;
+        let outcome = inner
+            .channels
+            .entry(short_channel_id.to_string())
+            .or_default();
+        outcome.last_error = Some(error.to_string());
+        outcome.last_error_time = Some(now());
+    }
+
+    /// Everything `ceebalancer-status` returns.
+    pub fn report(&self, config: &Config, store: &Store) -> StatusReport {
+        let inner = self.inner.lock().unwrap();
+        let mut channels: HashMap<String, ChannelStatus> = store
+            .channels()
+            .into_iter()
+            .map(|(short_channel_id, history)| {
+                let balance_ratio = history
+                    .snapshots
+                    .last()
+                    .filter(|s| s.amount_msat > 0)
+                    .map(|s| s.our_amount_msat as f64 / s.amount_msat as f64);
+                let applied = history.last_applied;
+                let status = ChannelStatus {
+                    balance_ratio,
+                    fee_ppm: applied.as_ref().map(|a| a.policy.fee_ppm),
+                    htlc_max_msat: applied.as_ref().map(|a| a.policy.htlc_max_msat),
+                    last_update: applied.as_ref().map(|a| a.timestamp),
+                    outcome: inner
+                        .channels
+                        .get(&short_channel_id)
+                        .cloned()
+                        .unwrap_or_default(),
+                };
+                (short_channel_id, status)
+            })
+            .collect();
+        for (short_channel_id, outcome) in &inner.channels {
+            channels
+                .entry(short_channel_id.clone())
+                .or_insert_with(|| ChannelStatus {
+                    outcome: outcome.clone(),
+                    ..ChannelStatus::default()
+                });
+        }
+        StatusReport {
+            config: config.clone(),
+            last_run: inner.last_run,
+            last_run_duration_ms: inner.last_run_duration.map(|d| d.as_millis() as u64),
+            next_run: inner.next_run,
+            successes: inner.successes,
+            failures: inner.failures,
+            channels,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChannelStatus {
+    /// Local share of the channel when it was last looked at.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance_ratio: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_ppm: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub htlc_max_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_update: Option<u64>,
+    #[serde(flatten)]
+    pub outcome: ChannelOutcome,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StatusReport {
+    pub config: Config,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_run: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_run_duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_run: Option<u64>,
+    pub successes: u64,
+    pub failures: u64,
+    pub channels: HashMap<String, ChannelStatus>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ChannelPolicy;
+    use anyhow::anyhow;
+
+    #[test]
+    fn report_combines_store_and_outcomes() {
+        let store = Store::new();
+        let policy = ChannelPolicy {
+            fee_ppm: 300,
+            htlc_max_msat: 900,
+            ..ChannelPolicy::default()
+        };
+        store.record_applied("1x1x1", &policy).unwrap();
+        let status = RunStatus::new();
+        status.record_success("1x1x1");
+        status.record_failure("2x2x2", &anyhow!("boom"));
+        status.record_run(100, Duration::from_millis(1500));
+        status.schedule_next(200);
+
+        let report = status.report(&Config::default(), &store);
+        assert_eq!(report.successes, 1);
+        assert_eq!(report.failures, 1);
+        assert_eq!(report.last_run_duration_ms, Some(1500));
+        assert_eq!(report.next_run, Some(200));
+        assert_eq!(report.channels["1x1x1"].fee_ppm, Some(300));
+        assert_eq!(
+            report.channels["2x2x2"].outcome.last_error,
+            Some("boom".to_string())
+        );
+    }
+}
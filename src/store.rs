@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::overrides::{self, Override};
+use crate::pid::PidState;
+use crate::{now, wire, ChannelPolicy};
+
+/// How many balance snapshots are kept in memory per channel.
+const MAX_SNAPSHOTS: usize = 1000;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Snapshot {
+    pub timestamp: u64,
+    pub our_amount_msat: u64,
+    pub amount_msat: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct AppliedPolicy {
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub policy: ChannelPolicy,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChannelHistory {
+    pub snapshots: Vec<Snapshot>,
+    pub last_applied: Option<AppliedPolicy>,
+    pub pid: Option<PidState>,
+}
+
+/// A line in the store file.  The file is only ever appended to and is
+/// replayed on startup to rebuild the in-memory view.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    Snapshot {
+        short_channel_id: String,
+        #[serde(flatten)]
+        snapshot: Snapshot,
+    },
+    Applied {
+        short_channel_id: String,
+        #[serde(flatten)]
+        policy: AppliedPolicy,
+    },
+    /// Controller state for `dynamic-fee-mode=pid`.
+    Pid {
+        short_channel_id: String,
+        #[serde(flatten)]
+        state: PidState,
+    },
+    /// Secret keying the privacy mode jitter, kept so a restart doesn't
+    /// re-jitter (and re-announce) every channel.
+    PrivacySeed { seed: u64 },
+    /// Sets, or with no policy clears, the override for a channel or peer.
+    Override {
+        id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        policy: Option<Override>,
+    },
+    /// Plugin options changed with `ceebalancer-setconfig`, by option name.
+    Config {
+        options: serde_json::Map<String, serde_json::Value>,
+    },
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    path: Option<PathBuf>,
+    channels: HashMap<String, ChannelHistory>,
+    overrides: HashMap<String, Override>,
+    config_options: serde_json::Map<String, serde_json::Value>,
+    privacy_seed: Option<u64>,
+}
+
+impl Inner {
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Snapshot {
+                short_channel_id,
+                snapshot,
+            } => {
+                let history = self.channels.entry(short_channel_id).or_default();
+                history.snapshots.push(snapshot);
+                if history.snapshots.len() > MAX_SNAPSHOTS {
+                    history.snapshots.remove(0);
+                }
+            }
+            Record::Applied {
+                short_channel_id,
+                policy,
+            } => {
+                self.channels
+                    .entry(short_channel_id)
+                    .or_default()
+                    .last_applied = Some(policy);
+            }
+            Record::Pid {
+                short_channel_id,
+                state,
+            } => {
+                self.channels.entry(short_channel_id).or_default().pid = Some(state);
+            }
+            Record::Override {
+                id,
+                policy: Some(policy),
+            } => {
+                self.overrides.insert(id, policy);
+            }
+            Record::Override { id, policy: None } => {
+                self.overrides.remove(&id);
+            }
+            Record::PrivacySeed { seed } => {
+                self.privacy_seed = Some(seed);
+            }
+            Record::Config { options } => {
+                self.config_options.extend(options);
+            }
+        }
+    }
+
+    /// The fewest records that rebuild the current view: the retained
+    /// snapshots and the latest of everything else.
+    fn records(&self) -> Vec<Record> {
+        let mut records = vec![];
+        if let Some(seed) = self.privacy_seed {
+            records.push(Record::PrivacySeed { seed });
+        }
+        if !self.config_options.is_empty() {
+            records.push(Record::Config {
+                options: self.config_options.clone(),
+            });
+        }
+        for (id, policy) in &self.overrides {
+            records.push(Record::Override {
+                id: id.clone(),
+                policy: Some(policy.clone()),
+            });
+        }
+        for (short_channel_id, history) in &self.channels {
+            for snapshot in &history.snapshots {
+                records.push(Record::Snapshot {
+                    short_channel_id: short_channel_id.clone(),
+                    snapshot: snapshot.clone(),
+                });
+            }
+            if let Some(policy) = &history.last_applied {
+                records.push(Record::Applied {
+                    short_channel_id: short_channel_id.clone(),
+                    policy: policy.clone(),
+                });
+            }
+            if let Some(state) = &history.pid {
+                records.push(Record::Pid {
+                    short_channel_id: short_channel_id.clone(),
+                    state: state.clone(),
+                });
+            }
+        }
+        records
+    }
+
+    fn append(&mut self, record: Record) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", serde_json::to_string(&record)?)?;
+        }
+        self.apply(record);
+        Ok(())
+    }
+}
+
+/// Per-channel history and the last policy applied to each channel.
+///
+/// Starts out in memory only; once `attach`ed to a file every record is also
+/// appended there so it survives a restart.
+#[derive(Clone, Debug, Default)]
+pub struct Store {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Store {
+    pub fn new() -> Store {
+        Store::default()
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Store, Error> {
+        let store = Store::new();
+        store.attach(path)?;
+        Ok(store)
+    }
+
+    /// Replays the file at `path` (if any), rewrites it with just what was
+    /// kept, and persists all further records to it.  A store without a
+    /// privacy seed gets one here, so nothing else has to write to it just
+    /// to read the seed.
+    pub fn attach<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut inner = self.inner.lock().unwrap();
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Record>(&line) {
+                    Ok(record) => inner.apply(record),
+                    Err(e) => log::warn!("Skipping unreadable store record {:?}: {}", line, e),
+                }
+            }
+        }
+        inner.privacy_seed.get_or_insert_with(rand::random);
+        compact(&path, &inner.records())?;
+        log::info!(
+            "Store attached at {:?} ({} channels)",
+            path,
+            inner.channels.len()
+        );
+        inner.path = Some(path);
+        Ok(())
+    }
+
+    pub fn record_snapshot(&self, channel: &wire::Channel) -> Result<(), Error> {
+        let short_channel_id = match &channel.short_channel_id {
+            Some(id) => id.clone(),
+            None => return Ok(()),
+        };
+        self.inner.lock().unwrap().append(Record::Snapshot {
+            short_channel_id,
+            snapshot: Snapshot {
+                timestamp: now(),
+                our_amount_msat: channel.our_amount_msat.msat(),
+                amount_msat: channel.amount_msat.msat(),
+            },
+        })
+    }
+
+    pub fn record_applied(
+        &self,
+        short_channel_id: &str,
+        policy: &ChannelPolicy,
+    ) -> Result<(), Error> {
+        self.inner.lock().unwrap().append(Record::Applied {
+            short_channel_id: short_channel_id.to_string(),
+            policy: AppliedPolicy {
+                timestamp: now(),
+                policy: policy.clone(),
+            },
+        })
+    }
+
+    pub fn record_pid(&self, short_channel_id: &str, state: &PidState) -> Result<(), Error> {
+        self.inner.lock().unwrap().append(Record::Pid {
+            short_channel_id: short_channel_id.to_string(),
+            state: state.clone(),
+        })
+    }
+
+    pub fn channel(&self, short_channel_id: &str) -> Option<ChannelHistory> {
+        self.inner
+            .lock()
+            .unwrap()
+            .channels
+            .get(short_channel_id)
+            .cloned()
+    }
+
+    pub fn channels(&self) -> HashMap<String, ChannelHistory> {
+        self.inner.lock().unwrap().channels.clone()
+    }
+
+    /// The seed written when the store was attached.  A store that never
+    /// was only keeps one in memory.
+    pub fn privacy_seed(&self) -> u64 {
+        *self
+            .inner
+            .lock()
+            .unwrap()
+            .privacy_seed
+            .get_or_insert_with(rand::random)
+    }
+
+    pub fn set_override(&self, id: &str, policy: Option<Override>) -> Result<(), Error> {
+        self.inner.lock().unwrap().append(Record::Override {
+            id: id.to_string(),
+            policy,
+        })
+    }
+
+    pub fn get_override(&self, id: &str) -> Option<Override> {
+        self.inner.lock().unwrap().overrides.get(id).cloned()
+    }
+
+    /// The override for the channel's short_channel_id, or failing that its
+    /// peer.
+    pub fn override_for(&self, channel: &wire::Channel) -> Option<Override> {
+        overrides::lookup(channel, |id| self.get_override(id))
+    }
+
+    pub fn overrides(&self) -> HashMap<String, Override> {
+        self.inner.lock().unwrap().overrides.clone()
+    }
+
+    /// Records options set at runtime.  Unless `persist`ed they only last
+    /// until the plugin restarts.
+    pub fn set_config_options(
+        &self,
+        options: serde_json::Map<String, serde_json::Value>,
+        persist: bool,
+    ) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        let record = Record::Config { options };
+        if persist {
+            inner.append(record)
+        } else {
+            inner.apply(record);
+            Ok(())
+        }
+    }
+
+    pub fn config_options(&self) -> serde_json::Map<String, serde_json::Value> {
+        self.inner.lock().unwrap().config_options.clone()
+    }
+}
+
+/// Every run appends to the file, so without this it would grow forever and
+/// take longer to replay each start.  Written aside and renamed over the
+/// original so a crash halfway leaves the old file intact.
+fn compact(path: &Path, records: &[Record]) -> Result<(), Error> {
+    let compacted = path.with_extension("compacting");
+    let mut file = File::create(&compacted)?;
+    for record in records {
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+    }
+    file.sync_all()?;
+    std::fs::rename(&compacted, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::primitives::Amount;
+
+    #[test]
+    fn records_survive_reopen() {
+        let path =
+            std::env::temp_dir().join(format!("ceebalancer-store-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let store = Store::open(&path).unwrap();
+        let channel = wire::Channel {
+            amount_msat: Amount { msat: 1000 },
+            our_amount_msat: Amount { msat: 250 },
+            connected: true,
+            peer_id: "039b9e260863e6d8735325b286931d73be9f8e766970ad4fe1cbcc470cd8964635"
+                .to_string(),
+            state: wire::ChannelState::CHANNELD_NORMAL,
+            funding_txid: "724ee70bc1670368c3db3c2ebed30d00fa595774356cebf509196c68a471ca91"
+                .to_string(),
+            funding_output: 0,
+            short_channel_id: Some("123x123x0".to_string()),
+            spendable_msat: None,
+        };
+        store.record_snapshot(&channel).unwrap();
+        let policy = ChannelPolicy {
+            fee_ppm: 300,
+            base_fee_msat: None,
+            htlc_min_msat: None,
+            htlc_max_msat: 900,
+        };
+        store.record_applied("123x123x0", &policy).unwrap();
+        let policy = ChannelPolicy {
+            fee_ppm: 400,
+            base_fee_msat: Some(0),
+            htlc_min_msat: Some(1),
+            htlc_max_msat: 800,
+        };
+        store.record_applied("123x123x0", &policy).unwrap();
+        let pid = PidState {
+            timestamp: 100,
+            error: -12.5,
+            integral: 250.0,
+        };
+        store.record_pid("123x123x0", &pid).unwrap();
+
+        let reopened = Store::open(&path).unwrap();
+        let history = reopened.channel("123x123x0").unwrap();
+        assert_eq!(history.snapshots.len(), 1);
+        assert_eq!(history.snapshots[0].our_amount_msat, 250);
+        assert_eq!(history.last_applied.unwrap().policy, policy);
+        assert_eq!(history.pid, Some(pid));
+        assert_eq!(
+            store.privacy_seed(),
+            Store::open(&path).unwrap().privacy_seed()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_is_compacted_on_open() {
+        let path =
+            std::env::temp_dir().join(format!("ceebalancer-compact-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let store = Store::open(&path).unwrap();
+        for fee_ppm in 0..10 {
+            let policy = ChannelPolicy {
+                fee_ppm,
+                base_fee_msat: None,
+                htlc_min_msat: None,
+                htlc_max_msat: 900,
+            };
+            store.record_applied("1x1x1", &policy).unwrap();
+        }
+        store
+            .set_override("2x2x2", Some(Override::default()))
+            .unwrap();
+        store.set_override("2x2x2", None).unwrap();
+        let lines = |path: &Path| std::fs::read_to_string(path).unwrap().lines().count();
+        // The privacy seed is written when the file is created
+        assert_eq!(lines(&path), 13);
+
+        let reopened = Store::open(&path).unwrap();
+        assert_eq!(lines(&path), 2);
+        let history = reopened.channel("1x1x1").unwrap();
+        assert_eq!(history.last_applied.unwrap().policy.fee_ppm, 9);
+        assert!(reopened.overrides().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn overrides_can_be_cleared() {
+        let store = Store::new();
+        let policy = Override {
+            fixed_fee: Some(0),
+            ..Override::default()
+        };
+        store.set_override("1x1x1", Some(policy.clone())).unwrap();
+        assert_eq!(store.get_override("1x1x1"), Some(policy));
+
+        store.set_override("1x1x1", None).unwrap();
+        assert!(store.overrides().is_empty());
+    }
+
+    #[test]
+    fn only_persisted_config_options_survive_reopen() {
+        let path =
+            std::env::temp_dir().join(format!("ceebalancer-config-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let store = Store::open(&path).unwrap();
+        let options = |name: &str, value| {
+            let mut options = serde_json::Map::new();
+            options.insert(name.to_string(), serde_json::json!(value));
+            options
+        };
+        store
+            .set_config_options(options("dynamic-fee-max", 2000), true)
+            .unwrap();
+        store
+            .set_config_options(options("dynamic-fee-min", 10), false)
+            .unwrap();
+        assert_eq!(store.config_options().len(), 2);
+
+        let reopened = Store::open(&path).unwrap();
+        assert_eq!(reopened.config_options(), options("dynamic-fee-max", 2000));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::analytics::{self, Flow};
+use crate::{now, Config, LightningBackend, PluginState};
+
+/// The local share a channel should sit at, given its net flow.  A channel
+/// that mostly forwards out (a sink, e.g. an exchange) drains, so it wants
+/// more of the balance on our side to start with; one that mostly fills
+/// up wants less.  Kept between the thresholds so the curve still has room
+/// on both sides.
+pub fn learned_ratio(flow: &Flow, config: &Config) -> Option<f64> {
+    let volume = flow.in_msat + flow.out_msat;
+    if volume == 0 {
+        return None;
+    }
+    let net = (flow.out_msat as f64 - flow.in_msat as f64) / volume as f64;
+    let reach = 0.5 - config.dynamic_fee_threshold as f64;
+    Some(0.5 + net * reach.max(0.0))
+}
+
+pub fn learn(channels: &BTreeMap<String, Flow>, config: &Config) -> HashMap<String, f64> {
+    channels
+        .iter()
+        .filter_map(|(id, flow)| Some((id.clone(), learned_ratio(flow, config)?)))
+        .collect()
+}
+
+/// Target ratios learned from the forwards within
+/// `dynamic_fee_target_learn_window`.  Without the forwarding history
+/// channels keep the configured target rather than missing their update.
+pub async fn learned_targets<B: LightningBackend>(
+    config: &Config,
+    state: &PluginState<B>,
+) -> HashMap<String, f64> {
+    if config.dynamic_fee_target_learn_window <= 0 {
+        return HashMap::new();
+    }
+    let window = config.dynamic_fee_target_learn_window as u64;
+    match state.backend.list_forwards().await {
+        Ok(forwards) => analytics::aggregate(&forwards, now(), &[window])
+            .pop()
+            .map(|report| learn(&report.channels, config))
+            .unwrap_or_default(),
+        Err(e) => {
+            log::warn!("Error looking up forwards to learn targets: {:?}", e);
+            HashMap::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn flow(in_msat: u64, out_msat: u64) -> Flow {
+        Flow {
+            in_msat,
+            out_msat,
+            ..Flow::default()
+        }
+    }
+
+    #[test]
+    fn sinks_want_more_local_balance() {
+        let config = Config {
+            dynamic_fee_threshold: 0.2,
+            ..Config::default()
+        };
+        let channels = BTreeMap::from([
+            ("1x1x1".to_string(), flow(0, 1000)),
+            ("2x2x2".to_string(), flow(750, 250)),
+            ("3x3x3".to_string(), flow(500, 500)),
+            ("4x4x4".to_string(), flow(0, 0)),
+        ]);
+        let targets = learn(&channels, &config);
+
+        // The threshold is an f32
+        assert!((targets["1x1x1"] - 0.8).abs() < 1e-6);
+        assert!((targets["2x2x2"] - 0.35).abs() < 1e-6);
+        assert_eq!(targets["3x3x3"], 0.5);
+        assert!(!targets.contains_key("4x4x4"));
+    }
+}
@@ -0,0 +1,384 @@
+use std::fmt;
+
+use crate::Config;
+
+/// Why an option was rejected.  Options are named as they are passed to
+/// lightningd, e.g. `dynamic-fee-min`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    /// The value has the wrong type or doesn't parse.
+    Unparsable {
+        option: &'static str,
+        reason: String,
+    },
+    /// The value parsed, but is outside what the option accepts.
+    OutOfRange {
+        option: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    /// The lower end of a range is above the upper end.
+    MinAboveMax {
+        min_option: &'static str,
+        max_option: &'static str,
+        min: i64,
+        max: i64,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Unparsable { option, reason } => write!(f, "{}: {}", option, reason),
+            ConfigError::OutOfRange {
+                option,
+                value,
+                expected,
+            } => write!(
+                f,
+                "{}: {} is out of range, expected {}",
+                option, value, expected
+            ),
+            ConfigError::MinAboveMax {
+                min_option,
+                max_option,
+                min,
+                max,
+            } => write!(
+                f,
+                "{} ({}) is greater than {} ({})",
+                min_option, min, max_option, max
+            ),
+        }
+    }
+}
+
+/// Every problem found with a configuration, not just the first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid configuration: ")?;
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+struct Checker {
+    errors: Vec<ConfigError>,
+}
+
+impl Checker {
+    fn at_least(&mut self, option: &'static str, value: i64, min: i64, expected: &'static str) {
+        if value < min {
+            self.errors.push(ConfigError::OutOfRange {
+                option,
+                value: value.to_string(),
+                expected,
+            });
+        }
+    }
+
+    /// Fractions are checked against [0, 1], or [0, 1) when `exclusive`.
+    fn fraction(&mut self, option: &'static str, value: f64, exclusive: bool) {
+        let too_high = if exclusive { value >= 1.0 } else { value > 1.0 };
+        if value.is_nan() || value < 0.0 || too_high {
+            self.errors.push(ConfigError::OutOfRange {
+                option,
+                value: format!("{}%", value * 100.0),
+                expected: if exclusive {
+                    "0 to 99 percent"
+                } else {
+                    "0 to 100 percent"
+                },
+            });
+        }
+    }
+
+    fn ordered(&mut self, min_option: &'static str, max_option: &'static str, min: i64, max: i64) {
+        if min > max {
+            self.errors.push(ConfigError::MinAboveMax {
+                min_option,
+                max_option,
+                min,
+                max,
+            });
+        }
+    }
+}
+
+impl Config {
+    /// Checks the values make sense together, e.g. that the fee range isn't
+    /// upside down and that nothing we divide by or sleep for is zero.
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut c = Checker { errors: vec![] };
+
+        c.at_least("dynamic-fee-min", self.dynamic_fee_min, 0, "0 or more");
+        c.at_least("dynamic-fee-max", self.dynamic_fee_max, 0, "0 or more");
+        c.ordered(
+            "dynamic-fee-min",
+            "dynamic-fee-max",
+            self.dynamic_fee_min,
+            self.dynamic_fee_max,
+        );
+        if !(0.0..0.5).contains(&self.dynamic_fee_threshold) {
+            c.errors.push(ConfigError::OutOfRange {
+                option: "dynamic-fee-threshold",
+                value: format!("{}%", self.dynamic_fee_threshold * 100.0),
+                expected: "0 to 49 percent",
+            });
+        }
+        c.at_least("dynamic-fee-width", self.dynamic_fee_width, 1, "1 or more");
+        c.at_least(
+            "dynamic-fee-update-interval",
+            self.dynamic_fee_update_interval,
+            1,
+            "1 second or more",
+        );
+        if !(self.dynamic_fee_target_ratio > 0.0 && self.dynamic_fee_target_ratio < 1.0) {
+            c.errors.push(ConfigError::OutOfRange {
+                option: "dynamic-fee-target-ratio",
+                value: format!("{}%", self.dynamic_fee_target_ratio * 100.0),
+                expected: "1 to 99 percent",
+            });
+        }
+        c.at_least(
+            "dynamic-fee-target-learn-window",
+            self.dynamic_fee_target_learn_window,
+            0,
+            "0 or more",
+        );
+        c.at_least(
+            "dynamic-fee-classify-window",
+            self.dynamic_fee_classify_window,
+            1,
+            "1 second or more",
+        );
+        c.at_least(
+            "dynamic-fee-pid-kp",
+            self.dynamic_fee_pid_kp,
+            0,
+            "0 or more",
+        );
+        c.at_least(
+            "dynamic-fee-pid-ki",
+            self.dynamic_fee_pid_ki,
+            0,
+            "0 or more",
+        );
+        c.at_least(
+            "dynamic-fee-pid-kd",
+            self.dynamic_fee_pid_kd,
+            0,
+            "0 or more",
+        );
+        c.at_least(
+            "dynamic-fee-forward-debounce",
+            self.dynamic_fee_forward_debounce,
+            0,
+            "0 or more",
+        );
+        c.at_least(
+            "dynamic-fee-deadband",
+            self.dynamic_fee_deadband,
+            0,
+            "0 or more",
+        );
+        c.fraction(
+            "dynamic-fee-htlc-max-deadband",
+            self.dynamic_fee_htlc_max_deadband as f64,
+            false,
+        );
+        c.fraction(
+            "dynamic-fee-hysteresis",
+            self.dynamic_fee_hysteresis as f64,
+            true,
+        );
+
+        c.at_least(
+            "dynamic-base-fee-min",
+            self.dynamic_base_fee_min,
+            0,
+            "0 or more",
+        );
+        c.at_least(
+            "dynamic-base-fee-max",
+            self.dynamic_base_fee_max,
+            0,
+            "0 or more",
+        );
+        c.ordered(
+            "dynamic-base-fee-min",
+            "dynamic-base-fee-max",
+            self.dynamic_base_fee_min,
+            self.dynamic_base_fee_max,
+        );
+
+        c.at_least(
+            "dynamic-htlc-min-normal",
+            self.dynamic_htlc_min_normal,
+            0,
+            "0 or more",
+        );
+        c.at_least(
+            "dynamic-htlc-min-raised",
+            self.dynamic_htlc_min_raised,
+            0,
+            "0 or more",
+        );
+        c.fraction(
+            "dynamic-htlc-min-depleted",
+            self.dynamic_htlc_min_depleted as f64,
+            false,
+        );
+        c.at_least(
+            "dynamic-htlc-min-dust",
+            self.dynamic_htlc_min_dust,
+            0,
+            "0 or more",
+        );
+        c.at_least(
+            "dynamic-htlc-min-dust-limit",
+            self.dynamic_htlc_min_dust_limit,
+            1,
+            "1 or more",
+        );
+
+        if self.dynamic_htlc_max_ladder.is_empty() {
+            c.errors.push(ConfigError::OutOfRange {
+                option: "dynamic-htlc-max-ladder",
+                value: "empty".to_string(),
+                expected: "at least one bucket",
+            });
+        }
+        if self.dynamic_htlc_max_ratio <= 0.0 || self.dynamic_htlc_max_ratio > 1.0 {
+            c.errors.push(ConfigError::OutOfRange {
+                option: "dynamic-htlc-max-ratio",
+                value: format!("{}%", self.dynamic_htlc_max_ratio * 100.0),
+                expected: "1 to 100 percent",
+            });
+        }
+        c.fraction(
+            "dynamic-htlc-max-reserve",
+            self.dynamic_htlc_max_reserve,
+            true,
+        );
+
+        c.fraction(
+            "dynamic-fee-privacy-jitter",
+            self.dynamic_fee_privacy_jitter,
+            false,
+        );
+        c.at_least(
+            "dynamic-fee-privacy-htlc-max-step",
+            self.dynamic_fee_privacy_htlc_max_step,
+            1,
+            "1 or more",
+        );
+        c.at_least(
+            "dynamic-fee-privacy-delay",
+            self.dynamic_fee_privacy_delay,
+            0,
+            "0 or more",
+        );
+
+        c.fraction(
+            "dynamic-fee-competitor-percentile",
+            self.dynamic_fee_competitor_percentile,
+            false,
+        );
+        c.at_least(
+            "dynamic-fee-competitor-min-count",
+            self.dynamic_fee_competitor_min_count,
+            1,
+            "1 or more",
+        );
+
+        if self.dynamic_fee_report_windows.is_empty() {
+            c.errors.push(ConfigError::OutOfRange {
+                option: "dynamic-fee-report-windows",
+                value: "empty".to_string(),
+                expected: "at least one window",
+            });
+        }
+
+        c.at_least(
+            "dynamic-fee-refill-floor-window",
+            self.dynamic_fee_refill_floor_window,
+            0,
+            "0 or more",
+        );
+        c.at_least(
+            "dynamic-rebalance-interval",
+            self.dynamic_rebalance_interval,
+            1,
+            "1 second or more",
+        );
+        c.at_least(
+            "dynamic-rebalance-amount",
+            self.dynamic_rebalance_amount,
+            1,
+            "1 or more",
+        );
+        c.fraction(
+            "dynamic-rebalance-budget",
+            self.dynamic_rebalance_budget,
+            false,
+        );
+        c.at_least(
+            "dynamic-rebalance-max-pairs",
+            self.dynamic_rebalance_max_pairs,
+            1,
+            "1 or more",
+        );
+
+        if c.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigErrors(c.errors))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn every_invalid_option_is_reported() {
+        let config = Config {
+            dynamic_fee_min: 500,
+            dynamic_fee_max: 100,
+            dynamic_fee_width: 0,
+            dynamic_fee_update_interval: -1,
+            ..Config::default()
+        };
+        let errors = config.validate().unwrap_err();
+
+        assert_eq!(errors.0.len(), 3);
+        assert_eq!(
+            errors.0[0],
+            ConfigError::MinAboveMax {
+                min_option: "dynamic-fee-min",
+                max_option: "dynamic-fee-max",
+                min: 500,
+                max: 100,
+            }
+        );
+        assert!(errors.to_string().contains("dynamic-fee-width"));
+        assert!(errors.to_string().contains("dynamic-fee-update-interval"));
+    }
+}
@@ -9,7 +9,9 @@ pub struct GetInfoResponse {
 }
 
 #[derive(Debug, Deserialize)]
-pub struct GetInfoResponseInfo {}
+pub struct GetInfoResponseInfo {
+    pub id: String,
+}
 
 #[derive(Debug, Deserialize)]
 pub struct ListChannelsResponse {
@@ -28,6 +30,91 @@ pub struct ListChannel {
     pub destination: String,
     pub short_channel_id: String,
     pub amount_msat: Amount,
+    pub base_fee_millisatoshi: u64,
+    pub fee_per_millionth: u32,
+    pub delay: u32,
+    pub htlc_minimum_msat: Amount,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub htlc_maximum_msat: Option<Amount>,
+    /// False while the channel is disabled, e.g. its end is offline.
+    pub active: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetRouteResponse {
+    method: String,
+    pub result: GetRouteResponseRoute,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetRouteResponseRoute {
+    pub route: Vec<RouteHop>,
+}
+
+/// One hop of a route, as `getroute` returns it and `sendpay` takes it.
+/// `delay` is relative to the current block height.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RouteHop {
+    pub id: String,
+    pub channel: String,
+    pub direction: u32,
+    pub amount_msat: Amount,
+    pub delay: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InvoiceResponse {
+    method: String,
+    pub result: Invoice,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Invoice {
+    pub payment_hash: String,
+    pub payment_secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListSendPaysResponse {
+    method: String,
+    pub result: ListSendPaysResponsePayments,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListSendPaysResponsePayments {
+    pub payments: Vec<SendPay>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SendPay {
+    pub payment_hash: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_msat: Option<Amount>,
+    pub amount_sent_msat: Amount,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListInvoicesResponse {
+    method: String,
+    pub result: ListInvoicesResponseInvoices,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListInvoicesResponseInvoices {
+    pub invoices: Vec<ListInvoice>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ListInvoice {
+    pub label: String,
+    pub payment_hash: String,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -105,4 +192,91 @@ pub struct Channel {
     pub funding_output: u32,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub short_channel_id: Option<String>,
+    /// What we can actually send right now, i.e. less the reserve and the
+    /// commitment fee.  `listfunds` doesn't report it, the backend fills it
+    /// in when it can.
+    #[serde(default)]
+    pub spendable_msat: Option<Amount>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListPeersResponse {
+    method: String,
+    pub result: ListPeersResponsePeers,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListPeersResponsePeers {
+    pub peers: Vec<Peer>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Peer {
+    pub id: String,
+    #[serde(default)]
+    pub channels: Vec<PeerChannel>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PeerChannel {
+    pub short_channel_id: Option<String>,
+    pub spendable_msat: Option<Amount>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListForwardsResponse {
+    method: String,
+    pub result: ListForwardsResponseForwards,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListForwardsResponseForwards {
+    pub forwards: Vec<Forward>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Forward {
+    pub in_channel: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub out_channel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_msat: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub out_msat: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_msat: Option<Amount>,
+    pub status: ForwardStatus,
+    pub received_time: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved_time: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForwardEventNotification {
+    pub forward_event: ForwardEvent,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardStatus {
+    Offered,
+    Settled,
+    Failed,
+    LocalFailed,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ForwardEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_hash: Option<String>,
+    pub in_channel: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub out_channel: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_msat: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub out_msat: Option<Amount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_msat: Option<Amount>,
+    pub status: ForwardStatus,
 }
@@ -0,0 +1,147 @@
+//! An in-process stand-in for lightningd's JSON-RPC socket, so the plugin can
+//! be tested end to end without a node.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Answers each method with a scripted result and records every request it
+/// gets.  Methods without a script get a JSON-RPC error.
+pub struct MockLightningd {
+    pub rpc_path: PathBuf,
+    dir: PathBuf,
+    responses: Arc<Mutex<HashMap<String, Value>>>,
+    requests: Arc<Mutex<Vec<Value>>>,
+}
+
+impl MockLightningd {
+    pub async fn start() -> MockLightningd {
+        let dir = std::env::temp_dir().join(format!(
+            "ceebalancer-mock-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let rpc_path = dir.join("lightning-rpc");
+        let listener = UnixListener::bind(&rpc_path).unwrap();
+
+        let mock = MockLightningd {
+            rpc_path,
+            dir,
+            responses: Arc::default(),
+            requests: Arc::default(),
+        };
+        // setchannel's answer doesn't matter to us, so it needs no script
+        mock.respond("setchannel", json!({ "channels": [] }));
+
+        let responses = mock.responses.clone();
+        let requests = mock.requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, responses.clone(), requests.clone()));
+            }
+        });
+        mock
+    }
+
+    /// Sets the `result` returned for `method`.
+    pub fn respond(&self, method: &str, result: Value) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert(method.to_string(), result);
+    }
+
+    /// The params of every call of `method`, oldest first.
+    pub fn requests(&self, method: &str) -> Vec<Value> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r["method"] == method)
+            .map(|r| r["params"].clone())
+            .collect()
+    }
+}
+
+impl Drop for MockLightningd {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+async fn serve(
+    mut stream: UnixStream,
+    responses: Arc<Mutex<HashMap<String, Value>>>,
+    requests: Arc<Mutex<Vec<Value>>>,
+) {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+        buf.extend_from_slice(&chunk[..n]);
+
+        // Requests aren't delimited, so take as many whole objects as we have
+        let mut parsed = serde_json::Deserializer::from_slice(&buf).into_iter::<Value>();
+        let mut batch = vec![];
+        while let Some(Ok(request)) = parsed.next() {
+            batch.push(request);
+        }
+        let consumed = parsed.byte_offset();
+        buf.drain(..consumed);
+
+        for request in batch {
+            requests.lock().unwrap().push(request.clone());
+            let method = request["method"].as_str().unwrap_or_default().to_string();
+            let response = match responses.lock().unwrap().get(&method) {
+                Some(result) => json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }),
+                None => json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "error": { "code": -32601, "message": format!("Unknown command '{}'", method) }
+                }),
+            };
+            let mut out = serde_json::to_vec(&response).unwrap();
+            out.extend_from_slice(b"\n\n");
+            if stream.write_all(&out).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+pub const PEER_ID: &str = "039b9e260863e6d8735325b286931d73be9f8e766970ad4fe1cbcc470cd8964635";
+
+/// A `listfunds` channel entry.
+pub fn funds_channel(
+    short_channel_id: &str,
+    amount_msat: u64,
+    ours_msat: u64,
+    connected: bool,
+) -> Value {
+    json!({
+        "peer_id": PEER_ID,
+        "connected": connected,
+        "state": "CHANNELD_NORMAL",
+        "short_channel_id": short_channel_id,
+        "our_amount_msat": format!("{}msat", ours_msat),
+        "amount_msat": format!("{}msat", amount_msat),
+        "funding_txid": "724ee70bc1670368c3db3c2ebed30d00fa595774356cebf509196c68a471ca91",
+        "funding_output": 0
+    })
+}
+
+pub fn listfunds(channels: Vec<Value>) -> Value {
+    json!({ "outputs": [], "channels": channels })
+}
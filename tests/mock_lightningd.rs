@@ -0,0 +1,114 @@
+mod common;
+
+use std::sync::Arc;
+
+use serde_json::json;
+
+use ceebalancer::{set_channel_fees, ClnBackend, Config, LightningBackend, PluginState};
+use common::{funds_channel, listfunds, MockLightningd};
+
+fn config() -> Arc<Config> {
+    Arc::new(Config {
+        dynamic_fees: true,
+        dynamic_fee_width: 10,
+        dynamic_fee_min: 10,
+        dynamic_fee_max: 500,
+        ..Config::default()
+    })
+}
+
+#[tokio::test]
+async fn only_online_channels_are_set() {
+    let mock = MockLightningd::start().await;
+    mock.respond(
+        "listfunds",
+        listfunds(vec![
+            funds_channel("103x1x0", 1_000_000, 0, true),
+            funds_channel("103x2x0", 1_000_000, 0, false),
+        ]),
+    );
+    let state = PluginState::new(ClnBackend::with_rpc_path(&mock.rpc_path));
+
+    set_channel_fees(config(), &state).await.unwrap();
+
+    let sent = mock.requests("setchannel");
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0]["id"], "103x1x0");
+    assert_eq!(sent[0]["feeppm"], 500);
+}
+
+#[tokio::test]
+async fn unchanged_policy_is_not_sent_twice() {
+    let mock = MockLightningd::start().await;
+    mock.respond(
+        "listfunds",
+        listfunds(vec![funds_channel("103x1x0", 1_000_000, 500_000, true)]),
+    );
+    let state = PluginState::new(ClnBackend::with_rpc_path(&mock.rpc_path));
+
+    set_channel_fees(config(), &state).await.unwrap();
+    set_channel_fees(config(), &state).await.unwrap();
+    assert_eq!(mock.requests("setchannel").len(), 1);
+
+    mock.respond(
+        "listfunds",
+        listfunds(vec![funds_channel("103x1x0", 1_000_000, 100_000, true)]),
+    );
+    set_channel_fees(config(), &state).await.unwrap();
+    assert_eq!(mock.requests("setchannel").len(), 2);
+}
+
+#[tokio::test]
+async fn dry_run_sends_nothing() {
+    let mock = MockLightningd::start().await;
+    mock.respond(
+        "listfunds",
+        listfunds(vec![funds_channel("103x1x0", 1_000_000, 0, true)]),
+    );
+    let state = PluginState::new(ClnBackend::with_rpc_path(&mock.rpc_path));
+    let config = Arc::new(Config {
+        dynamic_fees_dry_run: true,
+        ..(*config()).clone()
+    });
+
+    let plans = set_channel_fees(config, &state).await.unwrap();
+
+    assert_eq!(plans.len(), 1);
+    assert_eq!(plans[0].proposed.as_ref().unwrap().fee_ppm, 500);
+    assert!(mock.requests("setchannel").is_empty());
+}
+
+#[tokio::test]
+async fn forwards_are_listed() {
+    let mock = MockLightningd::start().await;
+    mock.respond(
+        "listforwards",
+        json!({
+            "forwards": [
+                {
+                    "in_channel": "103x2x0",
+                    "out_channel": "103x1x0",
+                    "in_msat": "100001001msat",
+                    "out_msat": "100000000msat",
+                    "fee_msat": "1001msat",
+                    "status": "settled",
+                    "received_time": 1560696342.368,
+                    "resolved_time": 1560696342.556
+                },
+                {
+                    "in_channel": "103x2x0",
+                    "in_msat": "5000msat",
+                    "status": "local_failed",
+                    "received_time": 1560696343.0
+                }
+            ]
+        }),
+    );
+    let backend = ClnBackend::with_rpc_path(&mock.rpc_path);
+
+    let forwards = backend.list_forwards().await.unwrap();
+
+    assert_eq!(forwards.len(), 2);
+    assert_eq!(forwards[0].fee_msat.unwrap().msat(), 1001);
+    assert_eq!(forwards[1].out_channel, None);
+}